@@ -1,16 +1,21 @@
 #!/bin/bash
 
-# get gpu json from ./gpu.sh
-gpu_info=$(/opt/omni-gpu-agent/gpu.sh)
+# get gpu json from ./gpu.sh, no gpus if nvidia-smi failed, its error is on stderr
+gpu_info=$(/opt/omni-gpu-agent/gpu.sh) || gpu_info="[]"
 
-# get prover json from ./prover.sh
-prover_info=$(/opt/omni-gpu-agent//prover.sh)
+# get host json from ./host.sh, null if it fails or isn't json so gpu info still goes out
+host_info=$(/opt/omni-gpu-agent/host.sh)
+if ! echo "$host_info" | jq -e . >/dev/null 2>&1; then
+    host_info=null
+fi
+
+# prover.log is fetched and parsed by the agent itself
 
 # combine result to a single json
 json_output=$(jq -nc \
     --argjson gpu_info "$gpu_info" \
-    --argjson prover_info "$prover_info" \
-    '{gpu_info: $gpu_info, prover_info: $prover_info}')
+    --argjson host_info "$host_info" \
+    '{gpu_info: $gpu_info, host_info: $host_info}')
 
 # output json
-echo "$json_output"
\ No newline at end of file
+echo "$json_output"
@@ -1,25 +1,52 @@
 #!/bin/bash
 
-# Get GPU information using nvidia-smi
-gpu_info=$(nvidia-smi --query-gpu=index,name,power.draw,temperature.gpu --format=csv,noheader,nounits)
+# fields queried from nvidia-smi, in the order they are read below
+query="index,name,power.draw,temperature.gpu,memory.used,memory.total,utilization.gpu,utilization.memory,fan.speed,clocks.sm,clocks.mem,power.limit,driver_version,uuid,pci.bus_id,ecc.errors.corrected.volatile.total,ecc.errors.uncorrected.volatile.total,pcie.link.gen.current,pcie.link.width.current,clocks_throttle_reasons.active"
+
+# Get GPU information using nvidia-smi, it prints its failures to stdout
+gpu_info=$(nvidia-smi --query-gpu=$query --format=csv,noheader,nounits)
+status=$?
+if [ $status -ne 0 ]; then
+    echo "nvidia-smi failed with exit status $status: $gpu_info" >&2
+    exit 1
+fi
 
 # Initialize JSON output
 json_output="["
 
 # Process each line of GPU information
-while IFS=, read -r index name power temperature; do
-    # Trim leading and trailing whitespace
-    index=$(echo "$index" | xargs)
-    name=$(echo "$name" | xargs)
-    power=$(echo "$power" | xargs)
-    temperature=$(echo "$temperature" | xargs)
-    
+while IFS=, read -r index name power temperature memory_used memory_total utilization_gpu utilization_memory fan_speed sm_clock mem_clock power_limit driver_version uuid pci_bus_id ecc_corrected ecc_uncorrected pcie_gen pcie_width throttle_reasons; do
+    # the here-string below still yields one empty line when there is no gpu
+    [ -z "$(echo "$index" | xargs)" ] && continue
+    # Trim leading and trailing whitespace, values stay strings, [N/A] included
     json_output+=$(jq -nc \
-        --arg index "$index" \
-        --arg name "$name" \
-        --arg power "$power" \
-        --arg temperature "$temperature" \
-        '{index: $index, name: $name, power: $power, temperature: $temperature}')
+        --arg index "$(echo "$index" | xargs)" \
+        --arg name "$(echo "$name" | xargs)" \
+        --arg power "$(echo "$power" | xargs)" \
+        --arg temperature "$(echo "$temperature" | xargs)" \
+        --arg memory_used "$(echo "$memory_used" | xargs)" \
+        --arg memory_total "$(echo "$memory_total" | xargs)" \
+        --arg utilization_gpu "$(echo "$utilization_gpu" | xargs)" \
+        --arg utilization_memory "$(echo "$utilization_memory" | xargs)" \
+        --arg fan_speed "$(echo "$fan_speed" | xargs)" \
+        --arg sm_clock "$(echo "$sm_clock" | xargs)" \
+        --arg mem_clock "$(echo "$mem_clock" | xargs)" \
+        --arg power_limit "$(echo "$power_limit" | xargs)" \
+        --arg driver_version "$(echo "$driver_version" | xargs)" \
+        --arg uuid "$(echo "$uuid" | xargs)" \
+        --arg pci_bus_id "$(echo "$pci_bus_id" | xargs)" \
+        --arg ecc_corrected "$(echo "$ecc_corrected" | xargs)" \
+        --arg ecc_uncorrected "$(echo "$ecc_uncorrected" | xargs)" \
+        --arg pcie_gen "$(echo "$pcie_gen" | xargs)" \
+        --arg pcie_width "$(echo "$pcie_width" | xargs)" \
+        --arg throttle_reasons "$(echo "$throttle_reasons" | xargs)" \
+        '{index: $index, name: $name, power: $power, temperature: $temperature,
+          memory_used: $memory_used, memory_total: $memory_total,
+          utilization_gpu: $utilization_gpu, utilization_memory: $utilization_memory,
+          fan_speed: $fan_speed, sm_clock: $sm_clock, mem_clock: $mem_clock,
+          power_limit: $power_limit, driver_version: $driver_version, uuid: $uuid,
+          pci_bus_id: $pci_bus_id, ecc_corrected: $ecc_corrected, ecc_uncorrected: $ecc_uncorrected,
+          pcie_gen: $pcie_gen, pcie_width: $pcie_width, throttle_reasons: $throttle_reasons}')
     json_output+=","
 done <<< "$gpu_info"
 
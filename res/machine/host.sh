@@ -0,0 +1,61 @@
+#!/bin/bash
+
+# host level telemetry, memory and disk sizes are in KiB
+
+hostname=$(hostname)
+os=$(. /etc/os-release && echo "$PRETTY_NAME")
+kernel=$(uname -r)
+uptime=$(cut -d' ' -f1 /proc/uptime)
+# host clock in utc, the prover stamps its log tables in utc whatever the timezone
+local_time=$(date -u +%Y-%m-%dT%H:%M:%S)
+read -r load_1 load_5 load_15 _ < /proc/loadavg
+
+cpu_model=$(grep -m1 "model name" /proc/cpuinfo | cut -d: -f2 | xargs)
+cpu_count=$(nproc)
+
+meminfo() {
+    awk -v key="$1:" '$1 == key {print $2}' /proc/meminfo
+}
+memory_total=$(meminfo MemTotal)
+memory_available=$(meminfo MemAvailable)
+swap_total=$(meminfo SwapTotal)
+swap_free=$(meminfo SwapFree)
+
+# usage of the filesystems holding / and /opt, prover.log lives in /opt
+disks=$(for path in / /opt; do
+    df -Pk "$path" | awk -v path="$path" 'NR == 2 {
+        printf "{\"path\":\"%s\",\"mount\":\"%s\",\"total\":\"%s\",\"used\":\"%s\",\"available\":\"%s\"}\n",
+            path, $6, $2, $3, $4
+    }'
+done | jq -sc .)
+
+# mac of the interface holding the default route, else the first non loopback one
+iface=$(ip route show default 2>/dev/null | awk '{print $5; exit}')
+if [ -z "$iface" ]; then
+    iface=$(ls /sys/class/net | grep -v '^lo$' | head -n 1)
+fi
+mac=$(cat "/sys/class/net/$iface/address" 2>/dev/null)
+
+jq -nc \
+    --arg hostname "$hostname" \
+    --arg os "$os" \
+    --arg kernel "$kernel" \
+    --arg uptime "$uptime" \
+    --arg local_time "$local_time" \
+    --arg load_1 "$load_1" \
+    --arg load_5 "$load_5" \
+    --arg load_15 "$load_15" \
+    --arg cpu_model "$cpu_model" \
+    --arg cpu_count "$cpu_count" \
+    --arg memory_total "$memory_total" \
+    --arg memory_available "$memory_available" \
+    --arg swap_total "$swap_total" \
+    --arg swap_free "$swap_free" \
+    --argjson disks "$disks" \
+    --arg mac "$mac" \
+    '{hostname: $hostname, os: $os, kernel: $kernel, uptime: $uptime, local_time: $local_time,
+      load_1: $load_1, load_5: $load_5, load_15: $load_15,
+      cpu_model: $cpu_model, cpu_count: $cpu_count,
+      memory_total: $memory_total, memory_available: $memory_available,
+      swap_total: $swap_total, swap_free: $swap_free,
+      disks: $disks, mac: $mac}'
@@ -7,47 +7,77 @@ fi
 
 VER=$1
 ADDR=$2
+STAGE=$3
 WORKER=$(hostname -I | awk '{print $1}')
-
-echo "This script will install nvidia driver/CUDA and ZKWORK prover in your ubuntu system, and auto configure it to run on boot"
+# the agent pushes cached downloads here, they are used instead of downloading,
+# the apt packages of install_driver and install_cuda still need the mirrors
+ARTIFACTS=/opt/artifacts
 
 # if no VER or ADDR quit
 if [ -z "$VER" ] || [ -z "$ADDR" ] ; then
-  echo "Usage: $0 <zkwork-version> <receive-address>"
+  echo "Usage: $0 <zkwork-version> <receive-address> [stage]"
   echo "Example: $0 0.2.3 aleo1spkkxewxj2dl2lgdps9xr28093p5nxsvjv55g2unmqfu0hmwyuysmf4qp3"
+  echo "Stages: install_driver install_cuda download_prover enable_service reboot, all stages run if omitted"
   exit 1
 fi
 
-echo "deb http://cz.archive.ubuntu.com/ubuntu jammy main" >> /etc/apt/sources.list && apt-get update && DEBIAN_FRONTEND=noninteractive apt-get install libc6 -y && apt-get install -y g++-11
-
-ubuntu-drivers install
-
-wget https://developer.download.nvidia.com/compute/cuda/repos/ubuntu2204/x86_64/cuda-keyring_1.1-1_all.deb
-
-dpkg -i cuda-keyring_1.1-1_all.deb
-apt-get update
-apt-get -y install cuda-toolkit-12-6
-apt-get -y install jq
-
-wget https://gh-proxy.com/https://github.com/6block/zkwork_aleo_gpu_worker/releases/download/v${VER}/aleo_prover-v${VER}_full.tar.gz
-
-tar -xvf aleo_prover-v${VER}_full.tar.gz -C /opt
-
-# geneate run/stop scripts
-echo "#!/bin/bash
+# stop at the first failing command so the agent sees which stage failed
+set -e
+
+# every stage can run again, the agent re-runs a deploy after failures
+install_driver() {
+  JAMMY="deb http://cz.archive.ubuntu.com/ubuntu jammy main"
+  grep -qxF "$JAMMY" /etc/apt/sources.list || echo "$JAMMY" >> /etc/apt/sources.list
+  apt-get update && DEBIAN_FRONTEND=noninteractive apt-get install libc6 -y && apt-get install -y g++-11
+
+  ubuntu-drivers install
+}
+
+install_cuda() {
+  if ! dpkg -s cuda-keyring > /dev/null 2>&1; then
+    KEYRING=$ARTIFACTS/cuda-keyring_1.1-1_all.deb
+    if [ ! -f "$KEYRING" ]; then
+      KEYRING=/tmp/cuda-keyring_1.1-1_all.deb
+      wget -q -O $KEYRING https://developer.download.nvidia.com/compute/cuda/repos/ubuntu2204/x86_64/cuda-keyring_1.1-1_all.deb
+    fi
+    dpkg -i $KEYRING
+  fi
+  rm -f $ARTIFACTS/cuda-keyring_1.1-1_all.deb
+  apt-get update
+  apt-get -y install cuda-toolkit-12-6
+  apt-get -y install jq
+}
+
+download_prover() {
+  TARBALL=$ARTIFACTS/aleo_prover-v${VER}_full.tar.gz
+  if [ ! -f "$TARBALL" ]; then
+    TARBALL=/tmp/aleo_prover-v${VER}_full.tar.gz
+    # -O overwrites a partial download of an earlier run
+    wget -q -O $TARBALL https://gh-proxy.com/https://github.com/6block/zkwork_aleo_gpu_worker/releases/download/v${VER}/aleo_prover-v${VER}_full.tar.gz
+  fi
+
+  tar -xvf $TARBALL -C /opt
+  rm -f $TARBALL
+  # read by zk-update.sh and the agent to tell the installed version
+  echo "$VER" > /opt/aleo_prover/VERSION
+}
+
+enable_service() {
+  # geneate run/stop scripts
+  echo "#!/bin/bash
 cd /opt/aleo_prover
 ./aleo_prover --address $ADDR --pool aleo.asia1.zk.work:10003 --pool aleo.hk.zk.work:10003 --pool aleo.jp.zk.work:10003 --custom_name $WORKER >> prover.log 2>&1
 echo \$! > aleo_prover.pid
 " > /opt/aleo_prover/start.sh
-chmod +x /opt/aleo_prover/start.sh
+  chmod +x /opt/aleo_prover/start.sh
 
-echo "#!/bin/bash
+  echo "#!/bin/bash
 kill -9 \$(cat /opt/aleo_prover/aleo_prover.pid)
 " > /opt/aleo_prover/stop.sh
-chmod +x /opt/aleo_prover/stop.sh
+  chmod +x /opt/aleo_prover/stop.sh
 
-# add systemd service to run PWD/aleo_prover/run_prover.sh
-echo "[Unit]
+  # add systemd service to run PWD/aleo_prover/run_prover.sh
+  echo "[Unit]
 Description=Aleo Prover
 After=network.target
 
@@ -63,8 +93,31 @@ Restart=always
 WantedBy=multi-user.target
 " > /etc/systemd/system/aleo.service
 
-systemctl daemon-reload
-systemctl enable aleo.service
-reboot
-
-
+  systemctl daemon-reload
+  systemctl enable aleo.service
+}
+
+# reboot in the background so the ssh session can return first
+reboot_host() {
+  nohup sh -c "sleep 2; reboot" > /dev/null 2>&1 &
+}
+
+case "$STAGE" in
+  install_driver) install_driver ;;
+  install_cuda) install_cuda ;;
+  download_prover) download_prover ;;
+  enable_service) enable_service ;;
+  reboot) reboot_host ;;
+  "")
+    echo "This script will install nvidia driver/CUDA and ZKWORK prover in your ubuntu system, and auto configure it to run on boot"
+    install_driver
+    install_cuda
+    download_prover
+    enable_service
+    reboot_host
+    ;;
+  *)
+    echo "Unknown stage: $STAGE"
+    exit 1
+    ;;
+esac
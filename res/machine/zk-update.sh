@@ -2,29 +2,61 @@
 
 # example usage:
 # curl https://gh-proxy.com/https://raw.githubusercontent.com/EarthLedger/aleo/refs/heads/main/zk-update.sh | bash -s 0.2.3 aleo14jy6z0h384m6tyqdlx6djflk6d0wp7fug4xqz05wp95ppqaqaygsgagv4t
+#
+# upgrades the prover installed by zk-ins.sh in place, drivers and cuda are left alone
+# pass - for the version or the address to keep the installed one
+# pass norestart as third argument to leave stopping and starting aleo.service to
+# the caller, the agent does that itself
 
 # if not root user, exit
 if [ "$EUID" -ne 0 ]
   then echo "Please run as root"
-  exit
+  exit 1
 fi
 
 VER=$1
 ADDR=$2
+RESTART=$3
 WORKER=$(hostname -I | awk '{print $1}')
+# pushed by the agent, used instead of downloading
+TARBALL=/opt/artifacts/aleo_prover-v${VER}_full.tar.gz
 
 echo "This script will update ZKWORK prover in your ubuntu system, and auto configure it to run on boot"
 
 # if no VER or ADDR quit
-if [ -z "$VER" ] || [ -z "$ADDR" ] ; then
-  echo "Usage: $0 <zkwork-version> <receive-address>"
+if [ -z "$VER" ] || [ -z "$ADDR" ] || { [ "$VER" = "-" ] && [ "$ADDR" = "-" ]; }; then
+  echo "Usage: $0 <zkwork-version|-> <receive-address|->"
   echo "Example: $0 0.2.3 aleo1spkkxewxj2dl2lgdps9xr28093p5nxsvjv55g2unmqfu0hmwyuysmf4qp3"
+  echo "         $0 0.2.4 -"
   exit 1
 fi
 
-wget https://gh-proxy.com/https://github.com/6block/zkwork_aleo_gpu_worker/releases/download/v${VER}/aleo_prover-v${VER}_full.tar.gz
+# stop at the first failing command so the agent sees what failed
+set -e
 
-tar -xvf aleo_prover-v${VER}_full.tar.gz -C /opt
+if [ "$ADDR" = "-" ]; then
+  ADDR=$(grep -o -- '--address [^ ]*' /opt/aleo_prover/start.sh | awk '{print $2}')
+  if [ -z "$ADDR" ]; then
+    echo "No installed prover to take the receive address from" >&2
+    exit 1
+  fi
+fi
+
+# download before stopping the service, so the prover keeps running meanwhile
+if [ "$VER" != "-" ] && [ ! -f "$TARBALL" ]; then
+  TARBALL=/tmp/aleo_prover-v${VER}_full.tar.gz
+  wget -q -O $TARBALL https://gh-proxy.com/https://github.com/6block/zkwork_aleo_gpu_worker/releases/download/v${VER}/aleo_prover-v${VER}_full.tar.gz
+fi
+
+if [ "$RESTART" != "norestart" ]; then
+  systemctl stop aleo.service || true
+fi
+
+if [ "$VER" != "-" ]; then
+  tar -xf $TARBALL -C /opt
+  rm -f $TARBALL
+  echo "$VER" > /opt/aleo_prover/VERSION
+fi
 
 # geneate run/stop scripts
 echo "#!/bin/bash
@@ -58,4 +90,6 @@ WantedBy=multi-user.target
 
 systemctl daemon-reload
 systemctl enable aleo.service
-systemctl restart aleo.service
+if [ "$RESTART" != "norestart" ]; then
+  systemctl restart aleo.service
+fi
@@ -0,0 +1,249 @@
+// cache of prover tarballs and other downloads, kept in ~/.lcd-agent/artifacts
+//
+// The agent downloads every artifact once, verifies its sha256 and pushes it to
+// the hosts over ssh before the install scripts run, the scripts use the pushed
+// copy instead of downloading. That saves each host a download of the prover
+// and the cuda keyring, it doesn't make hosts without internet access work:
+// zk-ins.sh still installs the driver, cuda and jq with apt-get from the public
+// mirrors.
+// A file copied into the cache dir by hand is used as if it was downloaded, its
+// checksum is recorded in `<name>.sha256` on first use. That is the only way to
+// provide a tarball, there is no upload command.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use lazy_static::lazy_static;
+use log::info;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::error::AgentError;
+
+const ARTIFACTS_DIR: &str = "artifacts";
+// a prover tarball is a few hundred MB, the uplink of a farm may be slow
+const DOWNLOAD_TIMEOUT_SECONDS: u64 = 1800;
+
+lazy_static! {
+    static ref CACHE_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+    // one download per artifact, the other hosts of a batch wait for it
+    static ref FETCHING: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>> =
+        Mutex::new(HashMap::new());
+    // checksums of files already hashed, by path, size and mtime
+    static ref HASHED: Mutex<HashMap<PathBuf, (u64, SystemTime, String)>> =
+        Mutex::new(HashMap::new());
+}
+
+// a file the hosts would download from the internet
+#[derive(Debug, Clone, PartialEq)]
+pub struct Artifact {
+    // file name in the cache and on the hosts
+    pub name: String,
+    pub url: String,
+}
+
+// a verified copy in the cache
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cached {
+    pub path: PathBuf,
+    pub sha256: String,
+}
+
+// create the cache dir in the agent home dir
+pub fn init(app_path: &str) -> Result<(), AgentError> {
+    let dir = Path::new(app_path).join(ARTIFACTS_DIR);
+    std::fs::create_dir_all(&dir)?;
+    *CACHE_DIR.lock().unwrap() = Some(dir);
+    Ok(())
+}
+
+// the cached copy of the artifact, downloaded first if missing. It has to match
+// sha256 when given, the checksum recorded when it was cached otherwise
+pub async fn fetch(artifact: &Artifact, sha256: Option<&str>) -> Result<Cached, AgentError> {
+    let dir = CACHE_DIR
+        .lock()
+        .unwrap()
+        .clone()
+        .ok_or_else(|| AgentError::CommandError("no artifact cache".to_owned()))?;
+    fetch_into(&dir, artifact, sha256).await
+}
+
+async fn fetch_into(
+    dir: &Path,
+    artifact: &Artifact,
+    sha256: Option<&str>,
+) -> Result<Cached, AgentError> {
+    let lock = FETCHING
+        .lock()
+        .unwrap()
+        .entry(artifact.name.clone())
+        .or_default()
+        .clone();
+    let _guard = lock.lock().await;
+
+    let path = dir.join(&artifact.name);
+    let downloaded = !tokio::fs::try_exists(&path).await?;
+    if downloaded {
+        download(&artifact.url, &path).await?;
+    }
+
+    let actual = file_sha256(&path).await?;
+    let sum_path = dir.join(format!("{}.sha256", artifact.name));
+    let expected = match sha256 {
+        Some(sha256) => sha256.to_lowercase(),
+        None => match tokio::fs::read_to_string(&sum_path).await {
+            Ok(text) if !downloaded => text.trim().to_owned(),
+            Ok(_) => actual.clone(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => actual.clone(),
+            Err(e) => return Err(e.into()),
+        },
+    };
+    if actual != expected {
+        // a broken download is fetched again next time, a file put there by hand stays
+        if downloaded {
+            tokio::fs::remove_file(&path).await?;
+        }
+        return Err(AgentError::ChecksumMismatch(
+            artifact.name.clone(),
+            expected,
+            actual,
+        ));
+    }
+    tokio::fs::write(&sum_path, format!("{}\n", actual)).await?;
+    Ok(Cached {
+        path,
+        sha256: actual,
+    })
+}
+
+// into a .part file first, an interrupted download is never taken for the artifact
+async fn download(url: &str, path: &Path) -> Result<(), AgentError> {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    info!("downloading {}", url);
+    let failed =
+        |e: reqwest::Error| AgentError::CommandError(format!("download of {} failed: {}", url, e));
+    let fetch = async {
+        let mut response = reqwest::get(url)
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(failed)?;
+        let mut file = tokio::fs::File::create(&part).await?;
+        while let Some(chunk) = response.chunk().await.map_err(failed)? {
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        Ok(())
+    };
+    let result = tokio::time::timeout(Duration::from_secs(DOWNLOAD_TIMEOUT_SECONDS), fetch)
+        .await
+        .unwrap_or(Err(AgentError::Timeout(DOWNLOAD_TIMEOUT_SECONDS)));
+    if let Err(e) = result {
+        let _ = tokio::fs::remove_file(&part).await;
+        return Err(e);
+    }
+    tokio::fs::rename(&part, path).await?;
+    info!("downloaded {} to {}", url, path.display());
+    Ok(())
+}
+
+// hex sha256 of the file, hashed again only when it changed
+async fn file_sha256(path: &Path) -> Result<String, AgentError> {
+    let metadata = tokio::fs::metadata(path).await?;
+    let stamp = (metadata.len(), metadata.modified()?);
+    if let Some((len, modified, sha256)) = HASHED.lock().unwrap().get(path) {
+        if (*len, *modified) == stamp {
+            return Ok(sha256.clone());
+        }
+    }
+
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    let sha256 = hex::encode(hasher.finalize());
+    HASHED
+        .lock()
+        .unwrap()
+        .insert(path.to_owned(), (stamp.0, stamp.1, sha256.clone()));
+    Ok(sha256)
+}
+
+// test
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    fn cache_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lcd-agent-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_file_sha256() {
+        let dir = cache_dir("sha256");
+        let path = dir.join("abc");
+        std::fs::write(&path, "abc").unwrap();
+        assert_eq!(file_sha256(&path).await.unwrap(), ABC_SHA256);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fetch_download_failure() {
+        let dir = cache_dir("download");
+        let artifact = Artifact {
+            name: "missing.tar.gz".to_owned(),
+            // nothing listens there
+            url: "http://127.0.0.1:1/missing.tar.gz".to_owned(),
+        };
+        let err = fetch_into(&dir, &artifact, None).await.unwrap_err();
+        assert!(err.to_string().contains("download of"), "{}", err);
+        assert!(!dir.join("missing.tar.gz").exists());
+        assert!(!dir.join("missing.tar.gz.part").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fetch_placed_by_hand() {
+        let dir = cache_dir("fetch");
+        let artifact = Artifact {
+            name: "aleo_prover-v0.2.3_full.tar.gz".to_owned(),
+            // never downloaded, the file is in the cache already
+            url: "http://127.0.0.1:1/none".to_owned(),
+        };
+        std::fs::write(dir.join(&artifact.name), "abc").unwrap();
+
+        let err = fetch_into(&dir, &artifact, Some(&"0".repeat(64)))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AgentError::ChecksumMismatch(..)));
+        // kept, it wasn't downloaded
+        assert!(dir.join(&artifact.name).exists());
+
+        let cached = fetch_into(&dir, &artifact, Some(&ABC_SHA256.to_uppercase()))
+            .await
+            .unwrap();
+        assert_eq!(cached.sha256, ABC_SHA256);
+        let recorded = std::fs::read_to_string(dir.join(format!("{}.sha256", artifact.name)));
+        assert_eq!(recorded.unwrap().trim(), ABC_SHA256);
+
+        // checked against the recorded checksum from now on
+        assert!(fetch_into(&dir, &artifact, None).await.is_ok());
+        std::fs::write(dir.join(&artifact.name), "abcd").unwrap();
+        let err = fetch_into(&dir, &artifact, None).await.unwrap_err();
+        assert!(matches!(err, AgentError::ChecksumMismatch(..)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,217 +1,1311 @@
+use std::net::Ipv4Addr;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use log::{error, info};
+use chrono::{DateTime, NaiveDateTime};
+use log::{error, info, warn};
 use serde::Deserialize;
 use serde::Serialize;
 use std::future::Future;
+use tokio::net::TcpStream;
+use tokio::select;
+use tokio::sync::{mpsc, Semaphore};
+use tokio_util::sync::CancellationToken;
 
+use crate::artifacts::{self, Artifact};
 use crate::error::AgentError;
-use crate::sh::{run_command, run_scp};
+use crate::prover::{ProverBackend, ProverConfig, ProverUpdate, RunningProver};
+use crate::sh::{
+    run_command, run_command_output, run_scp, tail_lines, AuthConfig, CommandOutput, SshTarget,
+};
 
-/*
-{
-    "index": "0",
-    "name": "NVIDIA GeForce RTX 3070",
-    "power": "172.27",
-    "temperature": "89"
-  }, */
-#[derive(Debug, Default, Clone, Serialize, Deserialize)]
-pub struct GpuInfo {
-    pub index: String,
-    pub name: String,
-    pub power: String,
-    pub temperature: String,
+mod metrics;
+mod plan;
+mod prover_log;
+mod rollout;
+mod targets;
+
+pub use metrics::{GpuInfo, HostInfo, ProverInfo};
+pub use plan::{plan_deploy, plan_update, HostPlan, Installed, PlanStep};
+pub use prover_log::{parse_prover_log, PROVER_LOG, PROVER_LOG_LINES};
+pub use rollout::{RolloutPlan, WaveDone};
+pub use targets::TargetSpec;
+
+// what happened when collecting from a host
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanStatus {
+    #[default]
+    Collected,
+    // nothing listening on the ssh port
+    Unreachable,
+    // credentials rejected
+    AuthFailed,
+    // host presented a key that doesn't match the known one
+    HostKeyChanged,
+    // port was open but the ssh session failed
+    ConnectionFailed,
+    Timeout,
+    // /opt/omni-gpu-agent/collect.sh is not installed
+    CollectorMissing,
+    JqMissing,
+    // nvidia-smi is missing or can't talk to the driver
+    NvidiaSmiFailed,
+    // collect.sh ran but its output isn't valid json
+    InvalidOutput,
+    CommandFailed,
 }
 
-/*
-{
-    "timestamp": "2024-10-13T10:22:57",
-    "gpu_index": "0",
-    "one_min": "445925",
-    "five_min": "445325",
-    "fifteen_min": "332917",
-    "thirty_min": "306742",
-    "sixty_min": "309270"
-  } */
-#[derive(Debug, Default, Clone, Serialize, Deserialize)]
-pub struct ProverInfo {
-    pub timestamp: String,
-    pub gpu_index: String,
-    pub one_min: String,
-    pub five_min: String,
-    pub fifteen_min: String,
-    pub thirty_min: String,
-    pub sixty_min: String,
+impl ScanStatus {
+    pub fn classify(err: &AgentError) -> Self {
+        match err {
+            AgentError::AuthError(_) => ScanStatus::AuthFailed,
+            AgentError::HostKeyChanged(_) => ScanStatus::HostKeyChanged,
+            AgentError::Timeout(_) => ScanStatus::Timeout,
+            AgentError::ParseError(_) => ScanStatus::InvalidOutput,
+            AgentError::CommandError(stderr) => {
+                ScanStatus::from_stderr(stderr).unwrap_or(ScanStatus::CommandFailed)
+            }
+            _ => ScanStatus::ConnectionFailed,
+        }
+    }
+
+    // collect.sh keeps going when a tool fails, so look at stderr even on success
+    fn from_stderr(stderr: &str) -> Option<Self> {
+        let missing = |tool: &str| {
+            stderr.lines().any(|line| {
+                line.contains(tool) && (line.contains("not found") || line.contains("No such file"))
+            })
+        };
+
+        // gpu.sh reports a failing nvidia-smi, a bare mention of it is no failure
+        let nvidia_smi_failed = stderr.lines().any(|line| {
+            line.contains("nvidia-smi failed") || line.contains("NVIDIA-SMI has failed")
+        });
+
+        if missing("collect.sh") {
+            Some(ScanStatus::CollectorMissing)
+        } else if nvidia_smi_failed || missing("nvidia-smi") {
+            Some(ScanStatus::NvidiaSmiFailed)
+        } else if missing("jq") {
+            Some(ScanStatus::JqMissing)
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct MachineInfo {
     #[serde(skip_deserializing)]
     pub ip: String,
+    #[serde(skip_deserializing)]
+    pub status: ScanStatus,
+    // last lines of stderr or the error message when status isn't collected
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    // unix time in milliseconds the agent collected this
+    #[serde(skip_deserializing)]
+    pub collected_at: u64,
+    // seconds between the latest hashrate table and the host clock,
+    // a large value means the prover stopped logging
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub prover_age: Option<u64>,
 
     pub gpu_info: Vec<GpuInfo>,
+    // parsed from prover.log by the agent, older collect.sh still sends it
+    #[serde(default)]
     pub prover_info: Vec<ProverInfo>,
+    // why prover_info is empty, e.g. no log yet or an unknown table format
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub prover_error: Option<String>,
+    // None for hosts without host.sh or when it failed
+    #[serde(default, deserialize_with = "metrics::lenient")]
+    pub host_info: Option<HostInfo>,
 }
 
-// impl json string to MachineInfo
-impl From<&str> for MachineInfo {
-    fn from(s: &str) -> Self {
-        // if failed to parse, return default MachineInfo
-        match serde_json::from_str(s) {
+// lines of stderr kept as error detail
+const ERROR_TAIL_LINES: usize = 5;
+
+impl MachineInfo {
+    // a host collecting failed on, without metrics
+    pub fn failed(ip: &str, err: &AgentError) -> Self {
+        let error = match err {
+            AgentError::CommandError(stderr) => tail_lines(stderr, ERROR_TAIL_LINES),
+            e => e.to_string(),
+        };
+        MachineInfo {
+            ip: ip.to_owned(),
+            status: ScanStatus::classify(err),
+            error: Some(error),
+            collected_at: now_millis(),
+            ..MachineInfo::default()
+        }
+    }
+
+    fn unreachable(target: &SshTarget) -> Self {
+        MachineInfo {
+            ip: target.ip.clone(),
+            status: ScanStatus::Unreachable,
+            error: Some(format!("port {} is closed", target.port)),
+            collected_at: now_millis(),
+            ..MachineInfo::default()
+        }
+    }
+
+    // needs host.sh reporting the host clock, both are utc
+    fn prover_age(&self) -> Option<u64> {
+        let now = self.host_info.as_ref()?.local_time?;
+        let latest = self.prover_info.iter().map(|p| p.timestamp).max()?;
+        Some((now - latest).num_seconds().max(0) as u64)
+    }
+
+    // parse the output of collect.sh
+    pub fn parse(ip: &str, output: &CommandOutput) -> Self {
+        let stderr_status = ScanStatus::from_stderr(&output.stderr);
+        let mut info = match serde_json::from_str::<MachineInfo>(&output.stdout) {
             Ok(info) => info,
             Err(e) => {
-                error!("Failed to parse json: {}", e);
-                MachineInfo::default()
+                error!("Failed to parse json from {}: {}", ip, e);
+                MachineInfo {
+                    status: stderr_status.unwrap_or(ScanStatus::InvalidOutput),
+                    error: Some(e.to_string()),
+                    ..MachineInfo::default()
+                }
             }
+        };
+
+        if let Some(status) = stderr_status {
+            info.status = status;
+            info.error = Some(tail_lines(&output.stderr, ERROR_TAIL_LINES));
         }
+        info.ip = ip.to_owned();
+        info.collected_at = now_millis();
+        info
     }
 }
 
 pub type AsyncOpType<T> = Pin<Box<dyn Future<Output = Result<T, AgentError>> + Send>>;
 
-pub fn deploy_to_ip(
+// deploy stages in the order they run on the target host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeployStage {
+    // read what is installed, decides which stages are skipped
+    Inspecting,
+    Uploading,
+    Extracting,
+    InstallingDriver,
+    InstallingCuda,
+    DownloadingProver,
+    EnablingService,
+    // when the prover changed but the host needs no reboot
+    RestartingProver,
+    // until the host is back with a new boot id
+    Rebooting,
+    // nvidia-smi talks to the driver
+    VerifyingDriver,
+    // the prover service is active
+    VerifyingService,
+    // the prover logs a hashrate
+    VerifyingHashrate,
+}
+
+impl DeployStage {
+    // apt and downloads depend on the site network, give them time
+    pub fn timeout_seconds(&self) -> u64 {
+        match self {
+            DeployStage::Inspecting => 60,
+            DeployStage::Uploading => 300,
+            DeployStage::Extracting => 60,
+            DeployStage::InstallingDriver => 1800,
+            DeployStage::InstallingCuda => 3600,
+            DeployStage::DownloadingProver => 1800,
+            DeployStage::EnablingService => 60,
+            DeployStage::RestartingProver => 60,
+            DeployStage::Rebooting => 30,
+            DeployStage::VerifyingDriver => 60,
+            DeployStage::VerifyingService => PROVER_RECOVERY_SECONDS,
+            DeployStage::VerifyingHashrate => rollout::VERIFY_SECONDS,
+        }
+    }
+
+    // zk-ins.sh stage name, None for stages run by the agent or the prover backend
+    fn script_stage(&self) -> Option<&'static str> {
+        match self {
+            DeployStage::InstallingDriver => Some("install_driver"),
+            DeployStage::InstallingCuda => Some("install_cuda"),
+            DeployStage::Rebooting => Some("reboot"),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StageStatus {
+    Started,
+    Succeeded,
+    Failed,
+    // its check passed, e.g. the driver is installed already
+    Skipped,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeployProgress {
+    pub ip: String,
+    pub stage: DeployStage,
+    pub status: StageStatus,
+    // unix time in milliseconds
+    pub timestamp: u64,
+    // last lines of stderr, or of the error for failed stages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stderr_tail: Option<String>,
+    // why a stage was skipped, or how a started one goes about it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+pub type ProgressSender = mpsc::UnboundedSender<DeployProgress>;
+
+// lines of stderr kept in progress events
+const STDERR_TAIL_LINES: usize = 20;
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn report_stage(
+    progress: &ProgressSender,
     ip: &str,
-    pwd: &str,
-    ver: &str,
-    addr: &str,
+    stage: DeployStage,
+    status: StageStatus,
+    stderr: Option<&str>,
+) {
+    let stderr_tail = stderr
+        .map(|s| tail_lines(s, STDERR_TAIL_LINES))
+        .filter(|s| !s.is_empty());
+    // receiver is gone when nobody listens for progress, deploy goes on anyway
+    let _ = progress.send(DeployProgress {
+        ip: ip.to_owned(),
+        stage,
+        status,
+        timestamp: now_millis(),
+        stderr_tail,
+        detail: None,
+    });
+}
+
+fn report_detail(
+    progress: &ProgressSender,
+    ip: &str,
+    stage: DeployStage,
+    status: StageStatus,
+    detail: Option<String>,
+) {
+    let _ = progress.send(DeployProgress {
+        ip: ip.to_owned(),
+        stage,
+        status,
+        timestamp: now_millis(),
+        stderr_tail: None,
+        detail,
+    });
+}
+
+// host setup script, unpacked from machine.tgz
+pub const INSTALL_SCRIPT: &str = "/opt/res/machine/zk-ins.sh";
+// pushed artifacts, zk-ins.sh and zk-update.sh use them instead of downloading
+const REMOTE_ARTIFACTS: &str = "/opt/artifacts";
+const CUDA_KEYRING: &str = "cuda-keyring_1.1-1_all.deb";
+
+async fn run_deploy_stage(
+    target: &SshTarget,
+    backend: &dyn ProverBackend,
+    config: &ProverConfig,
+    stage: DeployStage,
+    timeout_seconds: u64,
+    progress: &ProgressSender,
+) -> Result<CommandOutput, AgentError> {
+    // the host downloads what the agent couldn't push, said in a started event
+    let push = |artifact: Artifact, sha256: Option<String>| async move {
+        let fallback = push_artifact(target, &artifact, sha256.as_deref(), timeout_seconds).await?;
+        if let Some(detail) = fallback {
+            report_detail(
+                progress,
+                &target.ip,
+                stage,
+                StageStatus::Started,
+                Some(detail),
+            );
+        }
+        Ok::<(), AgentError>(())
+    };
+    match stage {
+        // deploy_to_ip inspects through detect_installed before any stage runs
+        DeployStage::Inspecting => Ok(CommandOutput::default()),
+        DeployStage::Uploading => {
+            run_scp(target, "./machine.tgz", "/opt/machine.tgz", timeout_seconds).await?;
+            Ok(CommandOutput::default())
+        }
+        // scp success, then perform remote tar -xvzf
+        DeployStage::Extracting => {
+            let cmd = "tar -xvzf /opt/machine.tgz -C /opt/";
+            run_command_output(target, cmd, timeout_seconds).await
+        }
+        DeployStage::DownloadingProver => {
+            push(backend.artifact(&config.version), config.sha256.clone()).await?;
+            backend.install(target, config, timeout_seconds).await
+        }
+        // the running prover keeps the old address until restarting_prover or the reboot
+        DeployStage::EnablingService => {
+            backend.stop(target, timeout_seconds).await?;
+            backend.configure(target, config, timeout_seconds).await
+        }
+        DeployStage::RestartingProver => {
+            backend.restart(target, timeout_seconds).await?;
+            Ok(CommandOutput::default())
+        }
+        DeployStage::Rebooting => {
+            let old_boot_id = boot_id(target).await?;
+            let output = run_script_stage(target, config, stage, timeout_seconds).await?;
+            wait_for_reboot(target, &old_boot_id, REBOOT_RECOVERY_SECONDS).await?;
+            Ok(output)
+        }
+        DeployStage::VerifyingDriver => {
+            run_command_output(target, "nvidia-smi -L", timeout_seconds).await
+        }
+        DeployStage::VerifyingService => {
+            wait_for_prover(target, backend, timeout_seconds).await?;
+            Ok(CommandOutput::default())
+        }
+        DeployStage::VerifyingHashrate => {
+            verify_hashrate(target, backend, timeout_seconds).await?;
+            Ok(CommandOutput::default())
+        }
+        DeployStage::InstallingCuda => {
+            let keyring = Artifact {
+                name: CUDA_KEYRING.to_owned(),
+                url: format!(
+                    "https://developer.download.nvidia.com/compute/cuda/repos/ubuntu2204/x86_64/{}",
+                    CUDA_KEYRING
+                ),
+            };
+            push(keyring, None).await?;
+            run_script_stage(target, config, stage, timeout_seconds).await
+        }
+        DeployStage::InstallingDriver => {
+            run_script_stage(target, config, stage, timeout_seconds).await
+        }
+    }
+}
+
+// copy the cached artifact to the host and check it arrived intact. Without a
+// cached copy, e.g. the agent is offline too, the host downloads it itself,
+// unless a checksum was asked for, only the cache can verify it. Returns why
+// the host downloads it itself
+async fn push_artifact(
+    target: &SshTarget,
+    artifact: &Artifact,
+    sha256: Option<&str>,
+    timeout_seconds: u64,
+) -> Result<Option<String>, AgentError> {
+    let remote = format!("{}/{}", REMOTE_ARTIFACTS, artifact.name);
+    let remove = format!("rm -f {}", remote);
+    let cached = match artifacts::fetch(artifact, sha256).await {
+        Ok(cached) => cached,
+        Err(e) if sha256.is_none() && !matches!(e, AgentError::ChecksumMismatch(..)) => {
+            let detail = format!("host downloads {} itself, not cached: {}", artifact.name, e);
+            warn!("{} {}", target.ip, detail);
+            // a copy left by an earlier deploy may be another build
+            run_command(target, &remove, CONTROL_COMMAND_TIMEOUT).await?;
+            return Ok(Some(detail));
+        }
+        Err(e) => return Err(e),
+    };
+
+    let mkdir = format!("mkdir -p {}", REMOTE_ARTIFACTS);
+    run_command(target, &mkdir, CONTROL_COMMAND_TIMEOUT).await?;
+    run_scp(
+        target,
+        &cached.path.to_string_lossy(),
+        &remote,
+        timeout_seconds,
+    )
+    .await?;
+    let output = run_command(target, &format!("sha256sum {}", remote), timeout_seconds).await?;
+    let actual = output.split_whitespace().next().unwrap_or_default();
+    if actual != cached.sha256 {
+        run_command(target, &remove, CONTROL_COMMAND_TIMEOUT).await?;
+        return Err(AgentError::ChecksumMismatch(
+            remote,
+            cached.sha256,
+            actual.to_owned(),
+        ));
+    }
+    Ok(None)
+}
+
+// perform remote shell script /opt/res/machine/zk-ins.sh stage by stage
+async fn run_script_stage(
+    target: &SshTarget,
+    config: &ProverConfig,
+    stage: DeployStage,
     timeout_seconds: u64,
+) -> Result<CommandOutput, AgentError> {
+    let script_stage = stage.script_stage().unwrap_or_default();
+    let cmd = format!(
+        "{} {} {} {}",
+        INSTALL_SCRIPT, config.version, config.address, script_stage
+    );
+    run_command_output(target, &cmd, timeout_seconds).await
+}
+
+// clock of the host in utc, the prover stamps its tables in utc whatever the timezone
+async fn host_time(target: &SshTarget) -> Result<NaiveDateTime, AgentError> {
+    let output = run_command(target, "date +%Y-%m-%dT%H:%M:%S%z", CONTROL_COMMAND_TIMEOUT).await?;
+    parse_host_time(&output)
+}
+
+fn parse_host_time(output: &str) -> Result<NaiveDateTime, AgentError> {
+    DateTime::parse_from_str(output.trim(), "%Y-%m-%dT%H:%M:%S%z")
+        .map(|time| time.naive_utc())
+        .map_err(|e| AgentError::ParseError(format!("host time {:?}: {}", output.trim(), e)))
+}
+
+// why the rows don't prove a hashrate since the given utc time, None when they do
+fn missing_hashrate(rows: &[ProverInfo], since: NaiveDateTime) -> Option<&'static str> {
+    if rows
+        .iter()
+        .any(|row| row.timestamp >= since && row.one_min > 0)
+    {
+        None
+    } else if rows.iter().all(|row| row.timestamp < since) {
+        Some("no new hashrate table yet")
+    } else {
+        Some("hashrate is zero")
+    }
+}
+
+// poll the prover until it reports a hashrate in a table logged after the check began,
+// tables from before a reboot or restart don't count
+async fn verify_hashrate(
+    target: &SshTarget,
+    backend: &dyn ProverBackend,
+    deadline_seconds: u64,
+) -> Result<(), AgentError> {
+    let since = host_time(target).await?;
+    let deadline = Instant::now() + Duration::from_secs(deadline_seconds);
+    loop {
+        tokio::time::sleep(Duration::from_secs(RECOVERY_POLL_SECONDS)).await;
+        let last_check = match backend.collect_stats(target, CONTROL_COMMAND_TIMEOUT).await {
+            Ok(rows) => match missing_hashrate(&rows, since) {
+                None => return Ok(()),
+                Some(reason) => reason.to_owned(),
+            },
+            Err(e) if denied(&e) => return Err(e),
+            Err(e) => e.to_string(),
+        };
+        if Instant::now() >= deadline {
+            return Err(AgentError::CommandError(format!(
+                "no hashrate within {} seconds, last check: {}",
+                deadline_seconds, last_check
+            )));
+        }
+    }
+}
+
+fn report_failed(progress: &ProgressSender, ip: &str, stage: DeployStage, e: &AgentError) {
+    error!("deploy {} failed at {:?}: {}", ip, stage, e);
+    report_stage(
+        progress,
+        ip,
+        stage,
+        StageStatus::Failed,
+        Some(&e.to_string()),
+    );
+}
+
+// inspect the host, then run the stages its plan doesn't keep, reporting each one
+// to progress, the prover has to show a hashrate within verify_seconds at the end
+pub fn deploy_to_ip(
+    target: &SshTarget,
+    backend: &Arc<dyn ProverBackend>,
+    config: &ProverConfig,
+    progress: ProgressSender,
+    verify_seconds: u64,
 ) -> AsyncOpType<()> {
-    let ip = ip.to_string();
-    let pwd = pwd.to_string();
-    let ver = ver.to_string();
-    let addr = addr.to_string();
+    let target = target.clone();
+    let backend = backend.clone();
+    let config = config.clone();
 
     Box::pin(async move {
-        let _output = run_scp(
-            &ip,
-            22,
-            "root",
-            &pwd,
-            "./machine.tgz",
-            "/opt/machine.tgz",
-            timeout_seconds,
-        )?;
+        let ip = &target.ip;
+        let inspecting = DeployStage::Inspecting;
+        report_stage(&progress, ip, inspecting, StageStatus::Started, None);
+        let timeout_seconds = inspecting.timeout_seconds();
+        let installed = match detect_installed(&target, backend.as_ref(), timeout_seconds).await {
+            Ok(installed) => installed,
+            Err(e) => {
+                report_failed(&progress, ip, inspecting, &e);
+                return Err(e);
+            }
+        };
+        report_stage(&progress, ip, inspecting, StageStatus::Succeeded, None);
 
-        // scp success, then perform remote tar -xvzf
-        let cmd = "tar -xvzf /opt/machine.tgz -C /opt/";
-        let _output = run_command(&ip, 22, "root", &pwd, cmd, timeout_seconds)?;
-        // perform remote shell script /opt/omni-gpu-agent/zk-ins.sh
-        // let cmd = "/opt/omni-gpu-agent/zk-ins.sh";
-        let cmd = format!("/opt/res/machine/zk-ins.sh {} {}", ver, addr);
-        let _output = run_command(&ip, 22, "root", &pwd, &cmd, timeout_seconds)?;
+        for step in plan_deploy(&installed, &config) {
+            let stage = step.stage;
+            if stage == inspecting {
+                continue;
+            }
+            if step.action == plan::Action::Keep {
+                report_detail(&progress, ip, stage, StageStatus::Skipped, step.detail);
+                continue;
+            }
+            if step.action == plan::Action::Blocked {
+                let detail = step.detail.unwrap_or_else(|| "blocked".to_owned());
+                let e = AgentError::CommandError(detail);
+                report_failed(&progress, ip, stage, &e);
+                return Err(e);
+            }
 
-        // this will be a long time depends on target machine network
+            let timeout_seconds = match stage {
+                DeployStage::VerifyingHashrate => verify_seconds,
+                _ => stage.timeout_seconds(),
+            };
+            report_stage(&progress, ip, stage, StageStatus::Started, None);
+            let run = run_deploy_stage(
+                &target,
+                backend.as_ref(),
+                &config,
+                stage,
+                timeout_seconds,
+                &progress,
+            );
+            match run.await {
+                Ok(output) => {
+                    report_stage(
+                        &progress,
+                        ip,
+                        stage,
+                        StageStatus::Succeeded,
+                        Some(&output.stderr),
+                    );
+                }
+                Err(e) => {
+                    report_failed(&progress, ip, stage, &e);
+                    return Err(e);
+                }
+            }
+        }
 
         Ok(())
     })
 }
 
-pub fn reboot_ip(ip: &str, pwd: &str, timeout_seconds: u64) -> AsyncOpType<()> {
-    let ip = ip.to_string();
-    let pwd = pwd.to_string();
+// timeout of each command while rebooting or waiting for a host
+const CONTROL_COMMAND_TIMEOUT: u64 = 10;
+// pause between checks whether a host or service is back
+const RECOVERY_POLL_SECONDS: u64 = 5;
+// gpu rigs take a while to post and load the driver
+pub const REBOOT_RECOVERY_SECONDS: u64 = 900;
+pub const PROVER_RECOVERY_SECONDS: u64 = 180;
+
+// changes on every boot
+async fn boot_id(target: &SshTarget) -> Result<String, AgentError> {
+    let output = run_command(
+        target,
+        "cat /proc/sys/kernel/random/boot_id",
+        CONTROL_COMMAND_TIMEOUT,
+    )
+    .await?;
+    Ok(output.trim().to_owned())
+}
+
+// the host is up but won't let us in, polling again won't help
+fn denied(e: &AgentError) -> bool {
+    matches!(e, AgentError::AuthError(_) | AgentError::HostKeyChanged(_))
+}
+
+// poll until the host is up with another boot id than `old_boot_id`
+pub async fn wait_for_reboot(
+    target: &SshTarget,
+    old_boot_id: &str,
+    recovery_seconds: u64,
+) -> Result<(), AgentError> {
+    let deadline = Instant::now() + Duration::from_secs(recovery_seconds);
+    loop {
+        tokio::time::sleep(Duration::from_secs(RECOVERY_POLL_SECONDS)).await;
+        match boot_id(target).await {
+            Ok(id) if id != old_boot_id => return Ok(()),
+            Ok(_) => {}
+            Err(e) if denied(&e) => return Err(e),
+            // down or still booting
+            Err(_) => {}
+        }
+        if Instant::now() >= deadline {
+            return Err(AgentError::Timeout(recovery_seconds));
+        }
+    }
+}
+
+// poll until the prover service is active, a failed poll is tried again
+async fn wait_for_prover(
+    target: &SshTarget,
+    backend: &dyn ProverBackend,
+    recovery_seconds: u64,
+) -> Result<(), AgentError> {
+    let deadline = Instant::now() + Duration::from_secs(recovery_seconds);
+    loop {
+        match backend.is_running(target, CONTROL_COMMAND_TIMEOUT).await {
+            Ok(true) => return Ok(()),
+            Ok(false) => {}
+            Err(e) if denied(&e) => return Err(e),
+            Err(e) => info!("{} prover check failed, retrying: {}", target.ip, e),
+        }
+        if Instant::now() >= deadline {
+            return Err(AgentError::Timeout(recovery_seconds));
+        }
+        tokio::time::sleep(Duration::from_secs(RECOVERY_POLL_SECONDS)).await;
+    }
+}
+
+// reboot the host and wait until it's back, returns the recovery time in ms
+pub fn reboot_ip(target: &SshTarget, recovery_seconds: u64) -> AsyncOpType<u64> {
+    let target = target.clone();
     Box::pin(async move {
-        let cmd = "reboot";
-        let _output = run_command(&ip, 22, "root", &pwd, cmd, timeout_seconds)?;
+        let old_boot_id = boot_id(&target).await?;
+        let started = Instant::now();
+        // in the background so the session can close before the host goes down
+        let cmd = "nohup sh -c 'sleep 2; reboot' > /dev/null 2>&1 &";
+        run_command(&target, cmd, CONTROL_COMMAND_TIMEOUT).await?;
 
-        Ok(())
+        wait_for_reboot(&target, &old_boot_id, recovery_seconds).await?;
+        let elapsed = started.elapsed().as_millis() as u64;
+        info!("{} is back after reboot in {} ms", target.ip, elapsed);
+        Ok(elapsed)
     })
 }
 
-pub fn scan_ip_detail(ip: &str, pwd: &str, timeout_seconds: u64) -> AsyncOpType<(MachineInfo)> {
-    let ip = ip.to_string();
-    let pwd = pwd.to_string();
+// failures are reported in the status of the returned MachineInfo
+pub fn scan_ip_detail(
+    target: &SshTarget,
+    backend: &Arc<dyn ProverBackend>,
+    timeout_seconds: u64,
+) -> AsyncOpType<MachineInfo> {
+    let target = target.clone();
+    let backend = backend.clone();
     Box::pin(async move {
         let cmd = "/opt/omni-gpu-agent/collect.sh";
 
-        match run_command(&ip, 22, "root", &pwd, cmd, timeout_seconds) {
-            Ok(output) => Ok(MachineInfo {
-                ip: ip.to_string(),
-                ..MachineInfo::from(output.as_str())
-            }),
-            Err(_e) => Ok(MachineInfo {
-                ip: ip.to_string(),
-                ..MachineInfo::default()
-            }),
+        let mut info = match run_command_output(&target, cmd, timeout_seconds).await {
+            Ok(output) => MachineInfo::parse(&target.ip, &output),
+            Err(e) => return Ok(MachineInfo::failed(&target.ip, &e)),
+        };
+
+        match backend.collect_stats(&target, timeout_seconds).await {
+            Ok(prover_info) => info.prover_info = prover_info,
+            Err(e) => {
+                info!("prover log of {}: {}", target.ip, e);
+                info.prover_info.clear();
+                info.prover_error = Some(e.to_string());
+            }
         }
+        info.prover_age = info.prover_age();
+        Ok(info)
     })
 }
 
-pub fn reboot_prover(ip: &str, pwd: &str, timeout_seconds: u64) -> AsyncOpType<()> {
-    let ip = ip.to_string();
-    let pwd = pwd.to_string();
+// a query answers an operator looking at one host, keep it short
+pub const QUERY_TIMEOUT_SECONDS: u64 = 5;
+
+// live status of one host, probing the port first so a dead host answers fast
+pub fn query_ip(
+    target: &SshTarget,
+    backend: &Arc<dyn ProverBackend>,
+    timeout_seconds: u64,
+) -> AsyncOpType<MachineInfo> {
+    let target = target.clone();
+    let backend = backend.clone();
     Box::pin(async move {
-        let cmd = "systemctl restart aleo.service";
-        let _output = run_command(&ip, 22, "root", &pwd, cmd, timeout_seconds)?;
+        if !probe_ssh(&target, PROBE_TIMEOUT_MILLIS).await? {
+            return Ok(MachineInfo::unreachable(&target));
+        }
+        scan_ip_detail(&target, &backend, timeout_seconds).await
+    })
+}
 
-        Ok(())
+// true when something accepts tcp connections on the ssh port
+pub fn probe_ssh(target: &SshTarget, timeout_millis: u64) -> AsyncOpType<bool> {
+    let target = target.clone();
+    Box::pin(async move {
+        let connect = TcpStream::connect((target.ip.as_str(), target.port));
+        match tokio::time::timeout(Duration::from_millis(timeout_millis), connect).await {
+            Ok(Ok(_stream)) => Ok(true),
+            Ok(Err(_)) | Err(_) => Ok(false),
+        }
     })
 }
 
-pub async fn batch_scan(
-    ip: &str,
-    pwd: &str,
-    runtime_handle: &tokio::runtime::Handle,
-) -> Result<Vec<MachineInfo>, AgentError> {
-    info!("scan ip: {}", ip);
+// restart the prover service and wait until it runs, returns the recovery time in ms
+pub fn reboot_prover(
+    target: &SshTarget,
+    backend: &Arc<dyn ProverBackend>,
+    recovery_seconds: u64,
+) -> AsyncOpType<u64> {
+    let target = target.clone();
+    let backend = backend.clone();
+    Box::pin(async move {
+        let started = Instant::now();
+        // a stopped prover is started, a running one restarted
+        if backend.is_running(&target, CONTROL_COMMAND_TIMEOUT).await? {
+            backend.restart(&target, CONTROL_COMMAND_TIMEOUT).await?;
+        } else {
+            backend.start(&target, CONTROL_COMMAND_TIMEOUT).await?;
+        }
+        wait_for_prover(&target, backend.as_ref(), recovery_seconds).await?;
+        let elapsed = started.elapsed().as_millis() as u64;
+        info!("{} prover is back in {} ms", target.ip, elapsed);
+        Ok(elapsed)
+    })
+}
 
-    let ip_prefix = ip.split('.').take(3).collect::<Vec<&str>>().join(".");
+// upgrade the prover in place and wait until it runs what was asked for
+pub fn update_ip(
+    target: &SshTarget,
+    backend: &Arc<dyn ProverBackend>,
+    update: &ProverUpdate,
+    recovery_seconds: u64,
+) -> AsyncOpType<RunningProver> {
+    let target = target.clone();
+    let backend = backend.clone();
+    let update = update.clone();
+    Box::pin(async move {
+        let timeout_seconds = DeployStage::DownloadingProver.timeout_seconds();
+        if let Some(version) = &update.version {
+            let artifact = backend.artifact(version);
+            push_artifact(
+                &target,
+                &artifact,
+                update.sha256.as_deref(),
+                timeout_seconds,
+            )
+            .await?;
+        }
+        // the script writes VERSION itself, only a process started since proves
+        // the new binary runs
+        backend.stop(&target, CONTROL_COMMAND_TIMEOUT).await?;
+        let updating = Instant::now();
+        let updated = backend.update(&target, &update, timeout_seconds).await;
+        // started either way, a failed update leaves the old prover in place
+        let start = backend.start(&target, CONTROL_COMMAND_TIMEOUT).await;
+        updated?;
+        start?;
+
+        let started = Instant::now();
+        let deadline = started + Duration::from_secs(recovery_seconds);
+        loop {
+            let check = async {
+                if !backend.is_running(&target, CONTROL_COMMAND_TIMEOUT).await? {
+                    return Ok(None);
+                }
+                backend
+                    .running(&target, CONTROL_COMMAND_TIMEOUT)
+                    .await
+                    .map(Some)
+            };
+            let last_check = match check.await {
+                Ok(Some(running)) if restarted(&running, &update, updating.elapsed()) => {
+                    info!(
+                        "{} prover runs {:?} after {} ms",
+                        target.ip,
+                        running.version,
+                        started.elapsed().as_millis()
+                    );
+                    return Ok(running);
+                }
+                Ok(Some(running)) => format!(
+                    "prover runs version {:?} address {:?} for {:?} seconds, expected {:?} {:?} started by the update",
+                    running.version, running.address, running.uptime, update.version, update.address
+                ),
+                Ok(None) => "prover service is not active".to_owned(),
+                Err(e) if denied(&e) => return Err(e),
+                Err(e) => e.to_string(),
+            };
+            if Instant::now() >= deadline {
+                return Err(AgentError::CommandError(format!(
+                    "update not applied within {} seconds, last check: {}",
+                    recovery_seconds, last_check
+                )));
+            }
+            tokio::time::sleep(Duration::from_secs(RECOVERY_POLL_SECONDS)).await;
+        }
+    })
+}
+
+// the prover runs what the update asked for in a process started after it began
+fn restarted(running: &RunningProver, update: &ProverUpdate, since_update: Duration) -> bool {
+    update.applied(running)
+        && running
+            .uptime
+            .is_some_and(|uptime| uptime <= since_update.as_secs())
+}
+
+// driver, cuda and prover of a host, read only
+async fn detect_installed(
+    target: &SshTarget,
+    backend: &dyn ProverBackend,
+    timeout_seconds: u64,
+) -> Result<Installed, AgentError> {
+    let output = run_command(target, plan::DETECT_COMMAND, timeout_seconds).await?;
+    Ok(Installed {
+        prover: backend.running(target, timeout_seconds).await?,
+        prover_running: backend.is_running(target, timeout_seconds).await?,
+        configured_address: backend.configured_address(target, timeout_seconds).await?,
+        ..plan::parse_installed(&output)
+    })
+}
+
+// inspect a host for a dry run, failures end up in the plan status
+pub fn inspect_ip(
+    target: &SshTarget,
+    backend: &Arc<dyn ProverBackend>,
+    timeout_seconds: u64,
+) -> AsyncOpType<HostPlan> {
+    let target = target.clone();
+    let backend = backend.clone();
+    Box::pin(async move {
+        let failed = |info: MachineInfo| HostPlan {
+            ip: info.ip,
+            status: info.status,
+            error: info.error,
+            ..HostPlan::default()
+        };
+        if !probe_ssh(&target, PROBE_TIMEOUT_MILLIS).await? {
+            return Ok(failed(MachineInfo::unreachable(&target)));
+        }
+        match detect_installed(&target, backend.as_ref(), timeout_seconds).await {
+            Ok(installed) => Ok(HostPlan {
+                ip: target.ip.clone(),
+                installed: Some(installed),
+                ..HostPlan::default()
+            }),
+            Err(e) => Ok(failed(MachineInfo::failed(&target.ip, &e))),
+        }
+    })
+}
+
+// state of one host while a batch is running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HostState {
+    NotStarted,
+    Running,
+    Completed,
+}
+
+// hosts of a cancelled batch, grouped by how far they got
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct BatchReport {
+    pub completed: Vec<String>,
+    pub aborted: Vec<String>,
+    pub not_started: Vec<String>,
+}
+
+// default number of hosts a scan works on at once
+pub const SCAN_CONCURRENCY: usize = 64;
+// the port probe only holds a socket, it can run wider than the ssh stage
+const PROBE_CONCURRENCY: usize = 256;
+const PROBE_TIMEOUT_MILLIS: u64 = 1000;
+// deploys download the driver, cuda and prover, keep them from saturating the uplink
+pub const DEPLOY_CONCURRENCY: usize = 8;
+// reboots and prover restarts, a site shouldn't lose all its hashrate at once
+pub const CONTROL_CONCURRENCY: usize = 16;
+// updates restart the prover and may download a new version
+pub const UPDATE_CONCURRENCY: usize = 8;
+
+// run op for every ip on the runtime and return the results in ip order
+// at most `concurrency` ops run at once, the rest wait in ip order
+// when cancel fires, every task is aborted (killing its child processes)
+// and AgentError::Cancelled reports which hosts got how far
+pub async fn run_batch<T, F>(
+    ips: Vec<String>,
+    op: F,
+    concurrency: usize,
+    runtime_handle: &tokio::runtime::Handle,
+    cancel: &CancellationToken,
+) -> Result<Vec<(String, Result<T, AgentError>)>, AgentError>
+where
+    T: Send + 'static,
+    F: Fn(&str) -> AsyncOpType<T>,
+{
+    let states = Arc::new(Mutex::new(vec![HostState::NotStarted; ips.len()]));
+    let slots = Arc::new(Semaphore::new(concurrency.max(1)));
     let mut handles = vec![];
-    for i in 1..256 {
-        let ip = format!("{}.{}", ip_prefix, i);
-        let pwd = pwd.to_string();
-        handles.push(runtime_handle.spawn(async move { scan_ip_detail(&ip, &pwd, 5).await }));
+    for (i, ip) in ips.iter().enumerate() {
+        let op = op(ip);
+        let states = states.clone();
+        let slots = slots.clone();
+        handles.push(runtime_handle.spawn(async move {
+            // the semaphore is never closed
+            let _slot = slots.acquire().await.unwrap();
+            states.lock().unwrap()[i] = HostState::Running;
+            let result = op.await;
+            states.lock().unwrap()[i] = HostState::Completed;
+            result
+        }));
     }
+    let abort_handles: Vec<_> = handles.iter().map(|h| h.abort_handle()).collect();
 
-    let result = futures::future::join_all(handles).await;
-
-    let mut machines = vec![];
-    for res in result {
-        match res {
-            Ok(Ok(machine)) => {
-                machines.push(machine);
+    select! {
+        result = futures::future::join_all(handles) => {
+            Ok(ips
+                .into_iter()
+                .zip(result)
+                .map(|(ip, res)| {
+                    let res = res.unwrap_or_else(|e| Err(AgentError::CommandError(e.to_string())));
+                    (ip, res)
+                })
+                .collect())
+        }
+        _ = cancel.cancelled() => {
+            // hold the states while aborting, an aborted task frees its slot
+            // and a queued one could mark itself running before the report
+            let states = states.lock().unwrap();
+            for handle in abort_handles {
+                handle.abort();
             }
-            Ok(Err(_e)) => {
-                // info!("scan_and_update_db error: {:?}", e);
+
+            let mut report = BatchReport::default();
+            for (ip, state) in ips.into_iter().zip(states.iter()) {
+                match state {
+                    HostState::NotStarted => report.not_started.push(ip),
+                    HostState::Running => report.aborted.push(ip),
+                    HostState::Completed => report.completed.push(ip),
+                }
             }
-            Err(_e) => {
-                // info!("scan_and_update_db join error: {:?}", e);
+            info!(
+                "batch cancelled, completed: {}, aborted: {}, not started: {}",
+                report.completed.len(),
+                report.aborted.len(),
+                report.not_started.len()
+            );
+            Err(AgentError::Cancelled(report))
+        }
+    }
+}
+
+// outcome of each host as soon as it is done, errors as their message
+pub type ResultSender<T> = mpsc::UnboundedSender<(String, Result<T, String>)>;
+
+// op of run_batch that also sends its outcome the moment the host is done,
+// run_batch itself only returns once every host is
+pub fn reporting<T>(ip: &str, op: AsyncOpType<T>, results: &ResultSender<T>) -> AsyncOpType<T>
+where
+    T: Clone + Send + 'static,
+{
+    let ip = ip.to_owned();
+    let results = results.clone();
+    Box::pin(async move {
+        let result = op.await;
+        // nobody listens once the command is gone
+        let _ = results.send((ip, result.as_ref().cloned().map_err(|e| e.to_string())));
+        result
+    })
+}
+
+// outcome of a scan
+#[derive(Debug, Clone, Default)]
+pub struct ScanReport {
+    // every host that answered on the ssh port, with its status
+    pub machines: Vec<MachineInfo>,
+    // nothing listening on the ssh port
+    pub unreachable: Vec<String>,
+    // machines whose credentials were rejected
+    pub auth_failed: Vec<String>,
+    // machines that failed for any other reason
+    pub failed: Vec<String>,
+}
+
+// run probe on every ip, then collect on the ones it returned true for. Returns the
+// others and the collect results. A cancel reports what the scan as a whole got
+// through: only probed hosts weren't scanned, unreachable ones are done
+async fn scan_in_phases<P, C>(
+    ips: Vec<String>,
+    probe: P,
+    collect: C,
+    concurrency: usize,
+    runtime_handle: &tokio::runtime::Handle,
+    cancel: &CancellationToken,
+) -> Result<(Vec<String>, Vec<(String, Result<MachineInfo, AgentError>)>), AgentError>
+where
+    P: Fn(&str) -> AsyncOpType<bool>,
+    C: Fn(&str) -> AsyncOpType<MachineInfo>,
+{
+    let probes = match run_batch(
+        ips,
+        probe,
+        PROBE_CONCURRENCY.max(concurrency),
+        runtime_handle,
+        cancel,
+    )
+    .await
+    {
+        Ok(probes) => probes,
+        Err(AgentError::Cancelled(mut cancelled)) => {
+            let probed = std::mem::take(&mut cancelled.completed);
+            cancelled.not_started.extend(probed);
+            sort_ips(&mut cancelled.not_started);
+            return Err(AgentError::Cancelled(cancelled));
+        }
+        Err(e) => return Err(e),
+    };
+
+    let mut reachable = vec![];
+    let mut unreachable = vec![];
+    for (ip, res) in probes {
+        match res {
+            Ok(true) => reachable.push(ip),
+            _ => unreachable.push(ip),
+        }
+    }
+    info!("scan probe done, reachable: {}", reachable.len());
+
+    match run_batch(reachable, collect, concurrency, runtime_handle, cancel).await {
+        Ok(result) => Ok((unreachable, result)),
+        Err(AgentError::Cancelled(mut cancelled)) => {
+            cancelled.completed.extend(unreachable);
+            sort_ips(&mut cancelled.completed);
+            Err(AgentError::Cancelled(cancelled))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// in address order, the order hosts were given in
+fn sort_ips(ips: &mut [String]) {
+    ips.sort_by_key(|ip| ip.parse::<Ipv4Addr>().ok());
+}
+
+// probe the ssh port of every target first, then collect from the reachable ones,
+// every machine goes to `found` as soon as it is collected
+pub async fn batch_scan(
+    targets: &TargetSpec,
+    auth: &AuthConfig,
+    backend: &Arc<dyn ProverBackend>,
+    found: &mpsc::UnboundedSender<MachineInfo>,
+    concurrency: usize,
+    runtime_handle: &tokio::runtime::Handle,
+    cancel: &CancellationToken,
+) -> Result<ScanReport, AgentError> {
+    let ips = targets.ips()?;
+    info!(
+        "scan {} hosts with {}: {:?}",
+        ips.len(),
+        backend.name(),
+        targets.include
+    );
+
+    let mut report = ScanReport::default();
+    let (unreachable, result) = scan_in_phases(
+        ips,
+        |ip| probe_ssh(&auth.target(ip), PROBE_TIMEOUT_MILLIS),
+        |ip| {
+            let scan = scan_ip_detail(&auth.target(ip), backend, 5);
+            let ip = ip.to_owned();
+            let found = found.clone();
+            Box::pin(async move {
+                let machine = scan.await.unwrap_or_else(|e| MachineInfo::failed(&ip, &e));
+                let _ = found.send(machine.clone());
+                Ok(machine)
+            })
+        },
+        concurrency,
+        runtime_handle,
+        cancel,
+    )
+    .await?;
+    report.unreachable = unreachable;
+
+    for (ip, res) in result {
+        let machine = res.unwrap_or_else(|e| MachineInfo::failed(&ip, &e));
+        match machine.status {
+            ScanStatus::Collected => {}
+            ScanStatus::AuthFailed => report.auth_failed.push(ip),
+            status => {
+                info!("scan {} failed: {:?} {:?}", ip, status, machine.error);
+                report.failed.push(ip);
             }
         }
+        report.machines.push(machine);
     }
 
-    info!("scan ip done: {}", machines.len());
+    info!(
+        "scan ip done, machines: {}, unreachable: {}, auth failed: {}, failed: {}",
+        report.machines.len(),
+        report.unreachable.len(),
+        report.auth_failed.len(),
+        report.failed.len()
+    );
 
-    Ok(machines)
+    Ok(report)
 }
 
-pub async fn batch_deploy(
-    ip: &str,
-    pwd: &str,
-    ver: &str,
-    addr: &str,
+// what deploy or update would do on each target, steps come from plan_steps
+pub async fn batch_plan<F>(
+    targets: &TargetSpec,
+    auth: &AuthConfig,
+    backend: &Arc<dyn ProverBackend>,
+    plan_steps: F,
+    concurrency: usize,
     runtime_handle: &tokio::runtime::Handle,
-) -> Result<(), AgentError> {
-    let ip_prefix = ip.split('.').take(3).collect::<Vec<&str>>().join(".");
-    let mut handles = vec![];
-    for i in 1..256 {
-        let ip = format!("{}.{}", ip_prefix, i);
-        let pwd = pwd.to_string();
-        let ver = ver.to_string();
-        let addr = addr.to_string();
-        handles.push(
-            runtime_handle.spawn(async move { deploy_to_ip(&ip, &pwd, &ver, &addr, 5).await }),
-        );
+    cancel: &CancellationToken,
+) -> Result<Vec<HostPlan>, AgentError>
+where
+    F: Fn(&Installed) -> Vec<PlanStep>,
+{
+    let results = run_batch(
+        targets.ips()?,
+        |ip| inspect_ip(&auth.target(ip), backend, QUERY_TIMEOUT_SECONDS),
+        concurrency,
+        runtime_handle,
+        cancel,
+    )
+    .await?;
+
+    let mut plans = vec![];
+    for (ip, res) in results {
+        let plan = res.unwrap_or_else(|e| HostPlan {
+            status: ScanStatus::classify(&e),
+            error: Some(e.to_string()),
+            ip,
+            ..HostPlan::default()
+        });
+        let steps = plan.installed.as_ref().map(&plan_steps).unwrap_or_default();
+        plans.push(plan.with_steps(steps));
     }
+    Ok(plans)
+}
+
+// outcome of a deploy
+#[derive(Debug, Default)]
+pub struct DeployReport {
+    pub results: Vec<(String, Result<(), AgentError>)>,
+    // one per wave that ran, empty without a rollout plan
+    pub waves: Vec<WaveDone>,
+    // hosts left out after the rollout halted
+    pub skipped: Vec<String>,
+}
 
-    let _result = futures::future::join_all(handles).await;
+// all targets at once, or wave by wave when there is a rollout plan,
+// each host's outcome goes to `results` as soon as it is done
+#[allow(clippy::too_many_arguments)]
+pub async fn batch_deploy(
+    targets: &TargetSpec,
+    auth: &AuthConfig,
+    backend: &Arc<dyn ProverBackend>,
+    config: &ProverConfig,
+    rollout: Option<&RolloutPlan>,
+    progress: &ProgressSender,
+    results: &ResultSender<()>,
+    concurrency: usize,
+    runtime_handle: &tokio::runtime::Handle,
+    cancel: &CancellationToken,
+) -> Result<DeployReport, AgentError> {
+    let ips = targets.ips()?;
+    let Some(plan) = rollout else {
+        let results = run_batch(
+            ips,
+            |ip| {
+                let deploy = deploy_to_ip(
+                    &auth.target(ip),
+                    backend,
+                    config,
+                    progress.clone(),
+                    rollout::VERIFY_SECONDS,
+                );
+                reporting(ip, deploy, results)
+            },
+            concurrency,
+            runtime_handle,
+            cancel,
+        )
+        .await?;
+        return Ok(DeployReport {
+            results,
+            ..DeployReport::default()
+        });
+    };
 
-    Ok(())
+    let mut report = DeployReport::default();
+    let mut waves = plan.waves(&ips).into_iter();
+    let verify_seconds = plan.verify_seconds;
+    while let Some(ips) = waves.next() {
+        let wave_results = match run_batch(
+            ips,
+            |ip| {
+                let deploy = deploy_to_ip(
+                    &auth.target(ip),
+                    backend,
+                    config,
+                    progress.clone(),
+                    verify_seconds,
+                );
+                reporting(ip, deploy, results)
+            },
+            concurrency,
+            runtime_handle,
+            cancel,
+        )
+        .await
+        {
+            Ok(results) => results,
+            // earlier waves count as completed, later ones as not started
+            Err(AgentError::Cancelled(mut cancelled)) => {
+                let done = report.results.into_iter().map(|(ip, _)| ip);
+                cancelled.completed.splice(0..0, done);
+                cancelled.not_started.extend(waves.flatten());
+                return Err(AgentError::Cancelled(cancelled));
+            }
+            Err(e) => return Err(e),
+        };
+
+        let failed = wave_results.iter().filter(|(_, res)| res.is_err()).count();
+        let wave = WaveDone {
+            wave: report.waves.len(),
+            canary: report.waves.is_empty() && plan.canary > 0,
+            hosts: wave_results.len(),
+            succeeded: wave_results.len() - failed,
+            failed,
+        };
+        info!(
+            "deploy wave {} done, {} of {} failed",
+            wave.wave, wave.failed, wave.hosts
+        );
+        report.results.extend(wave_results);
+        let halts = plan.halts(&wave);
+        report.waves.push(wave);
+        if halts {
+            report.skipped = waves.flatten().collect();
+            error!(
+                "deploy halted after wave {}, {} hosts skipped",
+                report.waves.len() - 1,
+                report.skipped.len()
+            );
+            break;
+        }
+    }
+    Ok(report)
 }
 
 // test
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::prover::default_backend;
     use lazy_static::lazy_static;
     use log::info;
     use std::sync::Once;
@@ -224,11 +1318,12 @@ mod tests {
     fn init_logger() {
         INIT.call_once(|| {
             std::env::set_var("RUST_LOG", "info");
-            env_logger::init();
+            let _ = env_logger::try_init();
         });
     }
 
     #[test]
+    #[ignore = "needs a reachable ssh host"]
     fn test_scan_ip_detail() {
         init_logger();
 
@@ -236,7 +1331,8 @@ mod tests {
         let timeout_seconds = 10;
 
         let rt = Runtime::new().unwrap();
-        let result = rt.block_on(scan_ip_detail(ip, "123456.", timeout_seconds));
+        let target = AuthConfig::password("123456.").target(ip);
+        let result = rt.block_on(scan_ip_detail(&target, &default_backend(), timeout_seconds));
         info!("result: {:?}", result);
 
         assert!(result.is_ok());
@@ -245,44 +1341,445 @@ mod tests {
     }
 
     #[test]
+    #[ignore = "needs a reachable ssh host"]
     fn test_deploy_to_ip() {
         init_logger();
 
         let ip = "192.168.187.90";
-        let timeout_seconds = 10;
+        let (progress, mut rx) = mpsc::unbounded_channel();
 
         let rt = Runtime::new().unwrap();
         let result = rt.block_on(deploy_to_ip(
-            ip,
-            "123456.",
-            "0.2.3",
-            "aleo1spkkxewxj2dl2lgdps9xr28093p5nxsvjv55g2unmqfu0hmwyuysmf4qp3",
-            timeout_seconds,
+            &AuthConfig::password("123456.").target(ip),
+            &default_backend(),
+            &ProverConfig {
+                version: "0.2.3".to_owned(),
+                address: "aleo1spkkxewxj2dl2lgdps9xr28093p5nxsvjv55g2unmqfu0hmwyuysmf4qp3"
+                    .to_owned(),
+                sha256: None,
+            },
+            progress,
+            rollout::VERIFY_SECONDS,
         ));
         info!("result: {:?}", result);
+        while let Ok(event) = rx.try_recv() {
+            info!("{:?}", event);
+        }
 
         assert!(result.is_ok());
-        let info = result.unwrap();
-        info!("{:?}", info);
     }
 
     #[test]
+    #[ignore = "needs a reachable ssh host"]
     fn test_batch_scan() {
         init_logger();
 
-        let ip = "192.168.11.1";
-        // let rt = Runtime::new().unwrap();
+        let targets = TargetSpec::new(&["192.168.11.0/24"]);
 
-        let rt = tokio::runtime::Builder::new_multi_thread()
-            .worker_threads(256)
-            .enable_all()
-            .build()
-            .unwrap();
+        let rt = Runtime::new().unwrap();
 
-        let result = rt.block_on(batch_scan(ip, "123456.", rt.handle()));
+        let cancel = CancellationToken::new();
+        let (found, _found_rx) = mpsc::unbounded_channel();
+        let result = rt.block_on(batch_scan(
+            &targets,
+            &AuthConfig::password("123456."),
+            &default_backend(),
+            &found,
+            SCAN_CONCURRENCY,
+            rt.handle(),
+            &cancel,
+        ));
 
         assert!(result.is_ok());
-        let machines = result.unwrap();
-        info!("{:?}", machines);
+        let report = result.unwrap();
+        info!("{:?}", report);
+    }
+
+    #[test]
+    fn test_machine_info_parse() {
+        let output = CommandOutput {
+            stdout: r#"{"gpu_info":[{"index":"0","name":"NVIDIA GeForce RTX 3070","power":"172.27","temperature":"89"}],"prover_info":[]}"#.to_owned(),
+            stderr: String::new(),
+        };
+        let info = MachineInfo::parse("10.0.0.1", &output);
+        assert_eq!(info.ip, "10.0.0.1");
+        assert!(info.host_info.is_none());
+        assert_eq!(info.status, ScanStatus::Collected);
+        assert_eq!(info.gpu_info.len(), 1);
+        assert!(info.error.is_none());
+
+        // a broken host section doesn't cost the gpu metrics
+        let output = CommandOutput {
+            stdout: r#"{"gpu_info":[],"prover_info":[],"host_info":{"hostname":1}}"#.to_owned(),
+            stderr: String::new(),
+        };
+        let info = MachineInfo::parse("10.0.0.1", &output);
+        assert_eq!(info.status, ScanStatus::Collected);
+        assert!(info.host_info.is_none());
+        assert!(info.collected_at > 0);
+
+        // jq missing leaves stdout empty
+        let output = CommandOutput {
+            stdout: "\n".to_owned(),
+            stderr: "/opt/omni-gpu-agent/gpu.sh: line 18: jq: command not found\n".to_owned(),
+        };
+        let info = MachineInfo::parse("10.0.0.1", &output);
+        assert_eq!(info.status, ScanStatus::JqMissing);
+        assert!(info.error.unwrap().contains("jq"));
+
+        let output = CommandOutput {
+            stdout: "garbage".to_owned(),
+            stderr: String::new(),
+        };
+        let info = MachineInfo::parse("10.0.0.1", &output);
+        assert_eq!(info.status, ScanStatus::InvalidOutput);
+    }
+
+    #[test]
+    fn test_prover_age() {
+        let output = CommandOutput {
+            stdout: r#"{"gpu_info":[],"host_info":{"hostname":"rig","os":"","kernel":"",
+                "uptime":"1","local_time":"2024-10-13T10:25:00","load_1":"0","load_5":"0",
+                "load_15":"0","cpu_model":"","cpu_count":"1","memory_total":"1",
+                "memory_available":"1","swap_total":"0","swap_free":"0","disks":[],"mac":""}}"#
+                .to_owned(),
+            stderr: String::new(),
+        };
+        let mut info = MachineInfo::parse("10.0.0.1", &output);
+        assert!(info.host_info.is_some());
+        assert_eq!(info.prover_age(), None);
+
+        info.prover_info = parse_prover_log(
+            "| 2024-10-13T10:22:57 |\n| gpu[*]: (1m - 1 5m - 1 15m - 1 30m - 1 60m - 1) |",
+        )
+        .unwrap();
+        assert_eq!(info.prover_age(), Some(123));
+    }
+
+    #[test]
+    fn test_restarted() {
+        let update = ProverUpdate {
+            version: Some("0.2.4".to_owned()),
+            address: None,
+            sha256: None,
+        };
+        let running = |uptime| RunningProver {
+            version: Some("0.2.4".to_owned()),
+            address: Some("aleo1abc".to_owned()),
+            uptime,
+        };
+        assert!(restarted(
+            &running(Some(20)),
+            &update,
+            Duration::from_secs(30)
+        ));
+        // VERSION says 0.2.4 but the process predates the update
+        assert!(!restarted(
+            &running(Some(600)),
+            &update,
+            Duration::from_secs(30)
+        ));
+        assert!(!restarted(&running(None), &update, Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_missing_hashrate_non_utc_host() {
+        // the table of 10:22:57 is stamped in utc, the host clock says 18:22 in utc+8
+        let rows = parse_prover_log(
+            "| 2024-10-13T10:22:57 |\n| gpu[*]: (1m - 5 5m - 5 15m - 5 30m - 5 60m - 5) |",
+        )
+        .unwrap();
+        let since = parse_host_time("2024-10-13T18:22:00+0800\n").unwrap();
+        assert_eq!(missing_hashrate(&rows, since), None);
+
+        // utc-5, the same table is older than the check
+        let since = parse_host_time("2024-10-13T05:23:00-0500").unwrap();
+        assert_eq!(
+            missing_hashrate(&rows, since),
+            Some("no new hashrate table yet")
+        );
+        assert!(parse_host_time("2024-10-13T05:23:00").is_err());
+    }
+
+    #[test]
+    fn test_scan_status_classify() {
+        let classify = |e: AgentError| MachineInfo::failed("10.0.0.1", &e).status;
+
+        assert_eq!(
+            classify(AgentError::AuthError("root@10.0.0.1:22".to_owned())),
+            ScanStatus::AuthFailed
+        );
+        assert_eq!(classify(AgentError::Timeout(5)), ScanStatus::Timeout);
+        assert_eq!(
+            classify(AgentError::SshError("disconnected".to_owned())),
+            ScanStatus::ConnectionFailed
+        );
+        assert_eq!(
+            classify(AgentError::CommandError(
+                "bash: /opt/omni-gpu-agent/collect.sh: No such file or directory".to_owned()
+            )),
+            ScanStatus::CollectorMissing
+        );
+        assert_eq!(
+            classify(AgentError::CommandError(
+                "NVIDIA-SMI has failed because it couldn't communicate with the NVIDIA driver"
+                    .to_owned()
+            )),
+            ScanStatus::NvidiaSmiFailed
+        );
+        assert_eq!(
+            classify(AgentError::CommandError("segfault".to_owned())),
+            ScanStatus::CommandFailed
+        );
+
+        let collected = |stderr: &str| {
+            let output = CommandOutput {
+                stdout: r#"{"gpu_info":[],"host_info":null}"#.to_owned(),
+                stderr: stderr.to_owned(),
+            };
+            MachineInfo::parse("10.0.0.1", &output).status
+        };
+        assert_eq!(
+            collected("nvidia-smi failed with exit status 9: NVIDIA-SMI has failed because it couldn't communicate with the NVIDIA driver"),
+            ScanStatus::NvidiaSmiFailed
+        );
+        assert_eq!(
+            collected("/opt/omni-gpu-agent/gpu.sh: line 7: nvidia-smi: command not found"),
+            ScanStatus::NvidiaSmiFailed
+        );
+        assert_eq!(
+            collected("nvidia-smi took 3s to answer"),
+            ScanStatus::Collected
+        );
+    }
+
+    #[test]
+    fn test_probe_ssh() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let mut auth = AuthConfig::password("x");
+            auth.port = port;
+            assert!(probe_ssh(&auth.target("127.0.0.1"), 1000).await.unwrap());
+
+            drop(listener);
+            assert!(!probe_ssh(&auth.target("127.0.0.1"), 1000).await.unwrap());
+        });
+    }
+
+    #[test]
+    fn test_query_unreachable() {
+        let rt = Runtime::new().unwrap();
+        let info = rt.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut auth = AuthConfig::password("x");
+            auth.port = listener.local_addr().unwrap().port();
+            drop(listener);
+            query_ip(&auth.target("127.0.0.1"), &default_backend(), 5)
+                .await
+                .unwrap()
+        });
+        assert_eq!(info.status, ScanStatus::Unreachable);
+        assert!(info.collected_at > 0);
+    }
+
+    #[test]
+    fn test_run_batch_cancel() {
+        let rt = Runtime::new().unwrap();
+        let cancel = CancellationToken::new();
+        let ips = vec!["10.0.0.1".to_owned(), "10.0.0.2".to_owned()];
+
+        let result = rt.block_on(async {
+            let trigger = cancel.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                trigger.cancel();
+            });
+            run_batch(
+                ips,
+                |ip| {
+                    let ip = ip.to_string();
+                    Box::pin(async move {
+                        if ip == "10.0.0.2" {
+                            tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+                        }
+                        Ok(())
+                    })
+                },
+                2,
+                rt.handle(),
+                &cancel,
+            )
+            .await
+        });
+
+        match result {
+            Err(AgentError::Cancelled(report)) => {
+                assert_eq!(report.completed, vec!["10.0.0.1"]);
+                assert_eq!(report.aborted, vec!["10.0.0.2"]);
+                assert!(report.not_started.is_empty());
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    // probe answers right away for .1 and .2, .3 and .4 are still being probed
+    #[test]
+    fn test_scan_cancel_while_probing() {
+        let rt = Runtime::new().unwrap();
+        let cancel = CancellationToken::new();
+        let ips: Vec<String> = (1..=4).map(|i| format!("10.0.0.{}", i)).collect();
+
+        let result = rt.block_on(async {
+            let trigger = cancel.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                trigger.cancel();
+            });
+            scan_in_phases(
+                ips,
+                |ip| {
+                    let ip = ip.to_owned();
+                    Box::pin(async move {
+                        if ip != "10.0.0.1" && ip != "10.0.0.2" {
+                            tokio::time::sleep(Duration::from_secs(10)).await;
+                        }
+                        Ok(ip == "10.0.0.1")
+                    })
+                },
+                |_ip| Box::pin(async { panic!("collected while probing") }),
+                3,
+                rt.handle(),
+                &cancel,
+            )
+            .await
+        });
+
+        match result {
+            Err(AgentError::Cancelled(report)) => {
+                assert!(report.completed.is_empty());
+                assert_eq!(report.aborted, vec!["10.0.0.3", "10.0.0.4"]);
+                // probed, but never scanned
+                assert_eq!(report.not_started, vec!["10.0.0.1", "10.0.0.2"]);
+            }
+            other => panic!("unexpected result: {:?}", other.map(|(u, r)| (u, r.len()))),
+        }
+    }
+
+    // .2 and .4 are unreachable, .1 is collected, .3 still being collected
+    #[test]
+    fn test_scan_cancel_while_collecting() {
+        let rt = Runtime::new().unwrap();
+        let cancel = CancellationToken::new();
+        let ips: Vec<String> = (1..=4).map(|i| format!("10.0.0.{}", i)).collect();
+
+        let result = rt.block_on(async {
+            let trigger = cancel.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                trigger.cancel();
+            });
+            scan_in_phases(
+                ips,
+                |ip| {
+                    let reachable = ip == "10.0.0.1" || ip == "10.0.0.3";
+                    Box::pin(async move { Ok(reachable) })
+                },
+                |ip| {
+                    let ip = ip.to_owned();
+                    Box::pin(async move {
+                        if ip == "10.0.0.3" {
+                            tokio::time::sleep(Duration::from_secs(10)).await;
+                        }
+                        Ok(MachineInfo {
+                            ip,
+                            ..Default::default()
+                        })
+                    })
+                },
+                2,
+                rt.handle(),
+                &cancel,
+            )
+            .await
+        });
+
+        match result {
+            Err(AgentError::Cancelled(report)) => {
+                assert_eq!(report.completed, vec!["10.0.0.1", "10.0.0.2", "10.0.0.4"]);
+                assert_eq!(report.aborted, vec!["10.0.0.3"]);
+                assert!(report.not_started.is_empty());
+            }
+            other => panic!("unexpected result: {:?}", other.map(|(u, r)| (u, r.len()))),
+        }
+    }
+
+    #[test]
+    fn test_run_batch_concurrency() {
+        let rt = Runtime::new().unwrap();
+        let cancel = CancellationToken::new();
+        let ips: Vec<String> = (1..=20).map(|i| format!("10.0.0.{}", i)).collect();
+        let running = Arc::new(Mutex::new((0, 0)));
+
+        let result = rt.block_on(run_batch(
+            ips,
+            |_ip| {
+                let running = running.clone();
+                Box::pin(async move {
+                    {
+                        let mut running = running.lock().unwrap();
+                        running.0 += 1;
+                        running.1 = running.1.max(running.0);
+                    }
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                    running.lock().unwrap().0 -= 1;
+                    Ok(())
+                })
+            },
+            3,
+            rt.handle(),
+            &cancel,
+        ));
+
+        assert_eq!(result.unwrap().len(), 20);
+        // (currently running, most running at once)
+        assert_eq!(*running.lock().unwrap(), (0, 3));
+    }
+
+    #[test]
+    fn test_run_batch_cancel_queued() {
+        let rt = Runtime::new().unwrap();
+        let cancel = CancellationToken::new();
+        let ips = vec!["10.0.0.1".to_owned(), "10.0.0.2".to_owned()];
+
+        let result = rt.block_on(async {
+            let trigger = cancel.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                trigger.cancel();
+            });
+            run_batch(
+                ips,
+                |_ip| {
+                    Box::pin(async move {
+                        tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+                        Ok(())
+                    })
+                },
+                1,
+                rt.handle(),
+                &cancel,
+            )
+            .await
+        });
+
+        match result {
+            Err(AgentError::Cancelled(report)) => {
+                assert_eq!(report.aborted, vec!["10.0.0.1"]);
+                assert_eq!(report.not_started, vec!["10.0.0.2"]);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
     }
 }
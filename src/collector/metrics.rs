@@ -0,0 +1,349 @@
+// typed gpu and prover metrics as reported by collect.sh
+//
+// The scripts print every value as a json string, e.g. "172.27" or "[N/A]",
+// so numbers are parsed leniently from either strings or json numbers.
+
+use std::str::FromStr;
+
+use chrono::NaiveDateTime;
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::Value;
+
+/*
+{
+    "index": "0",
+    "name": "NVIDIA GeForce RTX 3070",
+    "power": "172.27",
+    "temperature": "89"
+  }, */
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GpuInfo {
+    #[serde(deserialize_with = "number")]
+    pub index: u32,
+    pub name: String,
+    // watts, None when nvidia-smi reports [N/A]
+    #[serde(deserialize_with = "optional_number")]
+    pub power: Option<f32>,
+    // degrees celsius
+    #[serde(deserialize_with = "optional_number")]
+    pub temperature: Option<u32>,
+
+    // the fields below are missing from hosts still running the old gpu.sh
+    // MiB
+    #[serde(default, deserialize_with = "optional_number")]
+    pub memory_used: Option<u64>,
+    #[serde(default, deserialize_with = "optional_number")]
+    pub memory_total: Option<u64>,
+    // percent
+    #[serde(default, deserialize_with = "optional_number")]
+    pub utilization_gpu: Option<u32>,
+    #[serde(default, deserialize_with = "optional_number")]
+    pub utilization_memory: Option<u32>,
+    #[serde(default, deserialize_with = "optional_number")]
+    pub fan_speed: Option<u32>,
+    // MHz
+    #[serde(default, deserialize_with = "optional_number")]
+    pub sm_clock: Option<u32>,
+    #[serde(default, deserialize_with = "optional_number")]
+    pub mem_clock: Option<u32>,
+    // watts
+    #[serde(default, deserialize_with = "optional_number")]
+    pub power_limit: Option<f32>,
+    #[serde(default, deserialize_with = "optional_text")]
+    pub driver_version: Option<String>,
+    #[serde(default, deserialize_with = "optional_text")]
+    pub uuid: Option<String>,
+    #[serde(default, deserialize_with = "optional_text")]
+    pub pci_bus_id: Option<String>,
+    // volatile ecc error counts, None on cards without ecc
+    #[serde(default, deserialize_with = "optional_number")]
+    pub ecc_corrected: Option<u64>,
+    #[serde(default, deserialize_with = "optional_number")]
+    pub ecc_uncorrected: Option<u64>,
+    #[serde(default, deserialize_with = "optional_number")]
+    pub pcie_gen: Option<u32>,
+    #[serde(default, deserialize_with = "optional_number")]
+    pub pcie_width: Option<u32>,
+    // clocks_throttle_reasons.active bitmask, 0x4 is sw power cap,
+    // 0x20 sw thermal slowdown, 0x40 hw thermal slowdown
+    #[serde(default, deserialize_with = "optional_hex")]
+    pub throttle_reasons: Option<u64>,
+}
+
+/*
+{
+    "timestamp": "2024-10-13T10:22:57",
+    "gpu_index": "0",
+    "one_min": "445925",
+    "five_min": "445325",
+    "fifteen_min": "332917",
+    "thirty_min": "306742",
+    "sixty_min": "309270"
+  } */
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProverInfo {
+    // utc time the prover logged the table, like the Z stamps of its log lines,
+    // verify_hashrate compares it against the host clock in utc
+    pub timestamp: NaiveDateTime,
+    pub gpu_index: GpuSlot,
+    // hashrate averaged over each window
+    #[serde(deserialize_with = "number")]
+    pub one_min: u64,
+    #[serde(deserialize_with = "number")]
+    pub five_min: u64,
+    #[serde(deserialize_with = "number")]
+    pub fifteen_min: u64,
+    #[serde(deserialize_with = "number")]
+    pub thirty_min: u64,
+    #[serde(deserialize_with = "number")]
+    pub sixty_min: u64,
+}
+
+// host level telemetry from host.sh, memory and disk sizes in KiB
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HostInfo {
+    pub hostname: String,
+    // PRETTY_NAME of /etc/os-release
+    pub os: String,
+    pub kernel: String,
+    #[serde(deserialize_with = "number")]
+    pub uptime: f64,
+    // clock of the host in utc when collected, missing from older host.sh
+    #[serde(default)]
+    pub local_time: Option<NaiveDateTime>,
+    #[serde(deserialize_with = "number")]
+    pub load_1: f32,
+    #[serde(deserialize_with = "number")]
+    pub load_5: f32,
+    #[serde(deserialize_with = "number")]
+    pub load_15: f32,
+    pub cpu_model: String,
+    #[serde(deserialize_with = "number")]
+    pub cpu_count: u32,
+    #[serde(deserialize_with = "number")]
+    pub memory_total: u64,
+    #[serde(deserialize_with = "number")]
+    pub memory_available: u64,
+    #[serde(deserialize_with = "number")]
+    pub swap_total: u64,
+    #[serde(deserialize_with = "number")]
+    pub swap_free: u64,
+    pub disks: Vec<DiskInfo>,
+    // of the interface holding the default route
+    #[serde(deserialize_with = "optional_text")]
+    pub mac: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DiskInfo {
+    // path asked about, e.g. /opt, and the mount point of its filesystem
+    pub path: String,
+    pub mount: String,
+    #[serde(deserialize_with = "number")]
+    pub total: u64,
+    #[serde(deserialize_with = "number")]
+    pub used: u64,
+    #[serde(deserialize_with = "number")]
+    pub available: u64,
+}
+
+// prover rows are per gpu, or "*" for the sum over all gpus
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum GpuSlot {
+    Gpu(u32),
+    #[default]
+    Total,
+}
+
+// same shape as the log: a number per gpu, "*" for the total
+impl Serialize for GpuSlot {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            GpuSlot::Gpu(index) => serializer.serialize_u32(*index),
+            GpuSlot::Total => serializer.serialize_str("*"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for GpuSlot {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match Value::deserialize(deserializer)? {
+            Value::String(s) if s.trim() == "*" => Ok(GpuSlot::Total),
+            value => parse_value(&value)
+                .map(GpuSlot::Gpu)
+                .map_err(de::Error::custom),
+        }
+    }
+}
+
+fn parse_value<T: FromStr>(value: &Value) -> Result<T, String> {
+    let text = match value {
+        Value::String(s) => s.trim().to_owned(),
+        Value::Number(n) => n.to_string(),
+        other => return Err(format!("expected a number, got {}", other)),
+    };
+    text.parse()
+        .map_err(|_| format!("expected a number, got {:?}", text))
+}
+
+// a number, possibly quoted
+fn number<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+{
+    parse_value(&Value::deserialize(deserializer)?).map_err(de::Error::custom)
+}
+
+// like number, but anything unparsable such as "" or "[N/A]" is None
+fn optional_number<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+{
+    Ok(parse_value(&Value::deserialize(deserializer)?).ok())
+}
+
+// a section that shouldn't fail the whole report when it's malformed
+pub fn lenient<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: de::DeserializeOwned,
+{
+    Ok(serde_json::from_value(Value::deserialize(deserializer)?).ok())
+}
+
+// text fields, nvidia-smi placeholders such as "[N/A]" are None
+fn optional_text<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let text = String::deserialize(deserializer)?;
+    let text = text.trim();
+    if text.is_empty() || text.starts_with('[') {
+        return Ok(None);
+    }
+    Ok(Some(text.to_owned()))
+}
+
+// a bitmask printed as 0x0000000000000004
+fn optional_hex<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let text = String::deserialize(deserializer)?;
+    let digits = text.trim().trim_start_matches("0x");
+    Ok(u64::from_str_radix(digits, 16).ok())
+}
+
+// test
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gpu_info() {
+        let gpu: GpuInfo = serde_json::from_str(
+            r#"{"index":"1","name":"NVIDIA GeForce RTX 3070","power":"172.27","temperature":"89"}"#,
+        )
+        .unwrap();
+        assert_eq!(gpu.index, 1);
+        assert_eq!(gpu.power, Some(172.27));
+        assert_eq!(gpu.temperature, Some(89));
+        // old gpu.sh output has none of the extended fields
+        assert_eq!(gpu.memory_total, None);
+        assert_eq!(gpu.throttle_reasons, None);
+
+        let gpu: GpuInfo =
+            serde_json::from_str(r#"{"index":0,"name":"x","power":"[N/A]","temperature":""}"#)
+                .unwrap();
+        assert_eq!(gpu.power, None);
+        assert_eq!(gpu.temperature, None);
+
+        assert!(serde_json::from_str::<GpuInfo>(
+            r#"{"index":"","name":"","power":"","temperature":""}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_gpu_info_extended() {
+        let gpu: GpuInfo = serde_json::from_str(
+            r#"{"index":"0","name":"NVIDIA GeForce RTX 3070","power":"172.27","temperature":"89",
+                "memory_used":"5120","memory_total":"8192","utilization_gpu":"100",
+                "utilization_memory":"45","fan_speed":"78","sm_clock":"1905","mem_clock":"7000",
+                "power_limit":"220.00","driver_version":"550.54.14",
+                "uuid":"GPU-1a2b3c4d-aaaa-bbbb-cccc-000000000000","pci_bus_id":"00000000:01:00.0",
+                "ecc_corrected":"[N/A]","ecc_uncorrected":"[N/A]","pcie_gen":"3","pcie_width":"16",
+                "throttle_reasons":"0x0000000000000004"}"#,
+        )
+        .unwrap();
+        assert_eq!(gpu.memory_used, Some(5120));
+        assert_eq!(gpu.memory_total, Some(8192));
+        assert_eq!(gpu.utilization_gpu, Some(100));
+        assert_eq!(gpu.fan_speed, Some(78));
+        assert_eq!(gpu.sm_clock, Some(1905));
+        assert_eq!(gpu.power_limit, Some(220.0));
+        assert_eq!(gpu.driver_version.as_deref(), Some("550.54.14"));
+        assert_eq!(gpu.pci_bus_id.as_deref(), Some("00000000:01:00.0"));
+        assert_eq!(gpu.ecc_corrected, None);
+        assert_eq!(gpu.pcie_gen, Some(3));
+        assert_eq!(gpu.pcie_width, Some(16));
+        assert_eq!(gpu.throttle_reasons, Some(4));
+
+        let gpu: GpuInfo = serde_json::from_str(
+            r#"{"index":"0","name":"x","power":"1","temperature":"1","fan_speed":"[N/A]",
+                "uuid":"[N/A]","throttle_reasons":"[Not Supported]"}"#,
+        )
+        .unwrap();
+        assert_eq!(gpu.fan_speed, None);
+        assert_eq!(gpu.uuid, None);
+        assert_eq!(gpu.throttle_reasons, None);
+    }
+
+    #[test]
+    fn test_host_info() {
+        let host: HostInfo = serde_json::from_str(
+            r#"{"hostname":"rig-07","os":"Ubuntu 22.04.4 LTS","kernel":"6.5.0-41-generic",
+                "uptime":"2834.61","load_1":"0.58","load_5":"0.60","load_15":"0.52",
+                "cpu_model":"Intel(R) Xeon(R) Processor","cpu_count":"8",
+                "memory_total":"16384000","memory_available":"5510216",
+                "swap_total":"0","swap_free":"0",
+                "disks":[{"path":"/","mount":"/","total":"264212084","used":"21618208","available":"79184068"},
+                         {"path":"/opt","mount":"/opt","total":"1000","used":"990","available":"10"}],
+                "mac":"02:fc:00:00:00:01"}"#,
+        )
+        .unwrap();
+        assert_eq!(host.cpu_count, 8);
+        assert_eq!(host.local_time, None);
+        assert_eq!(host.load_5, 0.6);
+        assert_eq!(host.memory_available, 5510216);
+        assert_eq!(host.disks[1].mount, "/opt");
+        assert_eq!(host.disks[1].available, 10);
+        assert_eq!(host.mac.as_deref(), Some("02:fc:00:00:00:01"));
+    }
+
+    #[test]
+    fn test_prover_info() {
+        let rows: Vec<ProverInfo> = serde_json::from_str(
+            r#"[{"timestamp":"2024-10-13T10:22:57","gpu_index":"0","one_min":"445925","five_min":"445325",
+                 "fifteen_min":"332917","thirty_min":"306742","sixty_min":"309270"},
+                {"timestamp":"2024-10-13T10:22:57","gpu_index":"*","one_min":"891850","five_min":"890650",
+                 "fifteen_min":"665834","thirty_min":"613484","sixty_min":"618540"}]"#,
+        )
+        .unwrap();
+        assert_eq!(rows[0].gpu_index, GpuSlot::Gpu(0));
+        assert_eq!(rows[0].one_min, 445925);
+        assert_eq!(rows[1].gpu_index, GpuSlot::Total);
+        assert_eq!(
+            rows[1].timestamp.to_string(),
+            "2024-10-13 10:22:57".to_owned()
+        );
+
+        let value = serde_json::to_value(&rows[1]).unwrap();
+        assert_eq!(value["gpu_index"], "*");
+        assert_eq!(value["one_min"], 891850);
+        assert_eq!(value["timestamp"], "2024-10-13T10:22:57");
+    }
+}
@@ -0,0 +1,552 @@
+// deploy and update plans
+//
+// A plan only reads from the hosts: which driver, cuda and prover are installed
+// and whether the prover runs. The steps say what the command does with that.
+// Deploy runs the plan of a host as its state machine, stages whose check passes
+// are skipped, so a deploy that failed or was cut off by a reboot resumes where
+// it stopped. The host is the state, nothing is kept on the agent. Dry runs only
+// return the plans.
+
+use serde::Serialize;
+
+use crate::collector::{DeployStage, ScanStatus};
+use crate::prover::{ProverConfig, ProverUpdate, RunningProver};
+
+// read only, prints key=value lines for parse_installed
+// the cuda package is the one zk-ins.sh installs
+pub const DETECT_COMMAND: &str = "\
+echo driver=$(nvidia-smi --query-gpu=driver_version --format=csv,noheader 2>/dev/null | head -n 1); \
+echo driver_module=$(awk '/Kernel Module/ {for (i = 1; i < NF; i++) if ($i ~ /^Module$/) {print $(i + 1); exit}}' /proc/driver/nvidia/version 2>/dev/null); \
+echo driver_package=$(dpkg -l 'nvidia-driver-*' 2>/dev/null | awk '/^ii/ {print $3; exit}'); \
+echo driver_installed=$(stat -c %Y /var/lib/dpkg/info/nvidia-driver-*.list 2>/dev/null | sort -n | tail -n 1); \
+echo booted=$(awk '/^btime/ {print $2}' /proc/stat); \
+if command -v jq > /dev/null; then echo jq=1; else echo jq=0; fi; \
+echo cuda=$(dpkg-query -W -f='${Version}' cuda-toolkit-12-6 2>/dev/null); \
+if [ -x /opt/res/machine/zk-update.sh ]; then echo scripts=1; else echo scripts=0; fi";
+
+// what is on a host before deploy or update touches it
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Installed {
+    // nvidia driver version, None without a working nvidia-smi
+    pub driver: Option<String>,
+    // version of the loaded kernel module, also of a driver installed without dpkg
+    pub driver_module: Option<String>,
+    // version of the installed driver package, loaded or not
+    pub driver_package: Option<String>,
+    // the host booted since the driver package was installed
+    pub booted_since_driver: bool,
+    // version of the cuda toolkit package
+    pub cuda: Option<String>,
+    // installed with cuda, collect.sh needs it
+    pub jq: bool,
+    // machine.tgz is unpacked, the update script is in place
+    pub scripts: bool,
+    pub prover: RunningProver,
+    // the prover service is active
+    pub prover_running: bool,
+    // receive address the service is set up with, running or not
+    pub configured_address: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    // nothing installed yet
+    Install,
+    // installed already, the command runs it again anyway
+    Reinstall,
+    // another version is installed
+    Upgrade,
+    // the receive address changes
+    Change,
+    // left as it is
+    Keep,
+    // steps that don't install anything, e.g. the reboot
+    Run,
+    // the command would fail at this step
+    Blocked,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlanStep {
+    pub stage: DeployStage,
+    pub action: Action,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+// what deploy or update would do on one host
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HostPlan {
+    pub ip: String,
+    // collected when the host could be inspected
+    pub status: ScanStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub installed: Option<Installed>,
+    pub steps: Vec<PlanStep>,
+    // rollout wave the host would be deployed in, 0 is the canary
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wave: Option<usize>,
+    // inspected and no step is blocked
+    pub ready: bool,
+}
+
+impl HostPlan {
+    pub fn with_steps(mut self, steps: Vec<PlanStep>) -> Self {
+        self.ready = self.status == ScanStatus::Collected
+            && steps.iter().all(|step| step.action != Action::Blocked);
+        self.steps = steps;
+        self
+    }
+}
+
+// driver, cuda and scripts from the output of DETECT_COMMAND
+pub fn parse_installed(text: &str) -> Installed {
+    let mut installed = Installed::default();
+    // unix times, the package file was written when the driver was installed
+    let mut driver_installed: Option<u64> = None;
+    let mut booted: Option<u64> = None;
+    for line in text.lines() {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let value = value.trim();
+        let value = (!value.is_empty()).then(|| value.to_owned());
+        match key.trim() {
+            "driver" => installed.driver = value,
+            "driver_module" => installed.driver_module = value,
+            "driver_package" => installed.driver_package = value,
+            "driver_installed" => driver_installed = value.and_then(|v| v.parse().ok()),
+            "booted" => booted = value.and_then(|v| v.parse().ok()),
+            "cuda" => installed.cuda = value,
+            "jq" => installed.jq = value.as_deref() == Some("1"),
+            "scripts" => installed.scripts = value.as_deref() == Some("1"),
+            _ => {}
+        }
+    }
+    installed.booted_since_driver =
+        matches!((driver_installed, booted), (Some(installed), Some(booted)) if installed < booted);
+    installed
+}
+
+fn step(stage: DeployStage, action: Action, detail: Option<String>) -> PlanStep {
+    PlanStep {
+        stage,
+        action,
+        detail,
+    }
+}
+
+fn keep(stage: DeployStage, detail: String) -> PlanStep {
+    step(stage, Action::Keep, Some(detail))
+}
+
+// the checks of every deploy stage, kept stages are skipped
+pub fn plan_deploy(installed: &Installed, config: &ProverConfig) -> Vec<PlanStep> {
+    // installed but not loaded, a reboot loads it unless the host had one already
+    let unloaded = installed.driver_package.is_some() && installed.driver.is_none();
+    let loaded = installed
+        .driver
+        .as_ref()
+        .or(installed.driver_module.as_ref());
+    let driver = match (&installed.driver_package, loaded) {
+        (Some(version), _) if unloaded && installed.booted_since_driver => step(
+            DeployStage::InstallingDriver,
+            Action::Blocked,
+            Some(format!(
+                "driver {} is installed but not loaded after a reboot, nvidia-smi fails",
+                version
+            )),
+        ),
+        (Some(version), _) => keep(
+            DeployStage::InstallingDriver,
+            format!("driver {} installed", version),
+        ),
+        // e.g. the .run installer of nvidia
+        (None, Some(version)) => keep(
+            DeployStage::InstallingDriver,
+            format!("driver {} loaded, installed without dpkg", version),
+        ),
+        (None, None) => step(DeployStage::InstallingDriver, Action::Install, None),
+    };
+    let cuda = match (&installed.cuda, installed.jq) {
+        (Some(version), true) => keep(
+            DeployStage::InstallingCuda,
+            format!("cuda {} installed", version),
+        ),
+        (Some(_), false) => step(
+            DeployStage::InstallingCuda,
+            Action::Install,
+            Some("jq is missing".to_owned()),
+        ),
+        (None, _) => step(DeployStage::InstallingCuda, Action::Install, None),
+    };
+
+    let prover = &installed.prover;
+    let download = match &prover.version {
+        None => step(DeployStage::DownloadingProver, Action::Install, None),
+        Some(version) if *version == config.version => keep(
+            DeployStage::DownloadingProver,
+            format!("prover {} installed", version),
+        ),
+        Some(version) => step(
+            DeployStage::DownloadingProver,
+            Action::Upgrade,
+            Some(format!("{} -> {}", version, config.version)),
+        ),
+    };
+    // the address of the running prover, the configured one while it is stopped,
+    // restarting_prover starts it then
+    let service = match (&prover.address, &installed.configured_address) {
+        (Some(address), _) if *address == config.address => keep(
+            DeployStage::EnablingService,
+            "service runs with this address".to_owned(),
+        ),
+        (None, Some(address)) if *address == config.address => keep(
+            DeployStage::EnablingService,
+            "service is set up with this address".to_owned(),
+        ),
+        (Some(address), _) | (None, Some(address)) => step(
+            DeployStage::EnablingService,
+            Action::Change,
+            Some(format!("{} -> {}", address, config.address)),
+        ),
+        (None, None) => step(DeployStage::EnablingService, Action::Install, None),
+    };
+
+    let changed = |step: &PlanStep| step.action != Action::Keep;
+    // a driver installed by an earlier run is loaded after the reboot only
+    let reboot = changed(&driver) || changed(&cuda) || unloaded;
+    let restart = if reboot {
+        keep(
+            DeployStage::RestartingProver,
+            "the reboot starts the prover".to_owned(),
+        )
+    } else if changed(&download) || changed(&service) || !installed.prover_running {
+        step(DeployStage::RestartingProver, Action::Run, None)
+    } else {
+        keep(
+            DeployStage::RestartingProver,
+            "prover is running".to_owned(),
+        )
+    };
+    let reboot = if reboot {
+        step(DeployStage::Rebooting, Action::Run, None)
+    } else {
+        keep(
+            DeployStage::Rebooting,
+            "driver and cuda are loaded".to_owned(),
+        )
+    };
+
+    vec![
+        step(DeployStage::Inspecting, Action::Run, None),
+        // cheap, and keeps the scripts on the host current
+        step(DeployStage::Uploading, Action::Run, None),
+        step(DeployStage::Extracting, Action::Run, None),
+        driver,
+        cuda,
+        download,
+        service,
+        restart,
+        reboot,
+        // checked on every deploy, also when nothing had to change
+        step(DeployStage::VerifyingDriver, Action::Run, None),
+        step(DeployStage::VerifyingService, Action::Run, None),
+        step(DeployStage::VerifyingHashrate, Action::Run, None),
+    ]
+}
+
+// update only touches the prover, drivers and cuda stay
+pub fn plan_update(installed: &Installed, update: &ProverUpdate) -> Vec<PlanStep> {
+    if !installed.scripts {
+        let detail = Some("zk-update.sh is missing, deploy the host first".to_owned());
+        return vec![step(
+            DeployStage::DownloadingProver,
+            Action::Blocked,
+            detail,
+        )];
+    }
+
+    let prover = &installed.prover;
+    let download = match (&update.version, &prover.version) {
+        (None, _) => step(DeployStage::DownloadingProver, Action::Keep, None),
+        (Some(wanted), None) => step(
+            DeployStage::DownloadingProver,
+            Action::Install,
+            Some(format!("installed version unknown, installs {}", wanted)),
+        ),
+        (Some(wanted), Some(version)) if wanted == version => step(
+            DeployStage::DownloadingProver,
+            Action::Reinstall,
+            Some(format!("prover {} installed", version)),
+        ),
+        (Some(wanted), Some(version)) => step(
+            DeployStage::DownloadingProver,
+            Action::Upgrade,
+            Some(format!("{} -> {}", version, wanted)),
+        ),
+    };
+    // zk-update.sh takes the address from the start script when none is given
+    let address = prover
+        .address
+        .as_ref()
+        .or(installed.configured_address.as_ref());
+    let service = match (&update.address, address) {
+        (None, None) => step(
+            DeployStage::EnablingService,
+            Action::Blocked,
+            Some("no receive address installed to keep".to_owned()),
+        ),
+        (Some(wanted), Some(address)) if wanted != address => step(
+            DeployStage::EnablingService,
+            Action::Change,
+            Some(format!("{} -> {}", address, wanted)),
+        ),
+        _ => step(
+            DeployStage::EnablingService,
+            Action::Keep,
+            Some("restarts the prover".to_owned()),
+        ),
+    };
+    vec![download, service]
+}
+
+// test
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn installed() -> Installed {
+        Installed {
+            prover: RunningProver {
+                version: Some("0.2.3".to_owned()),
+                address: Some("aleo1abc".to_owned()),
+                uptime: Some(3600),
+            },
+            prover_running: true,
+            ..parse_installed(
+                "driver=550.54.14\ndriver_package=550.54.14-0ubuntu1\njq=1\ncuda=12.6.2-1\nscripts=1\n",
+            )
+        }
+    }
+
+    fn action(steps: &[PlanStep], stage: DeployStage) -> Option<Action> {
+        steps.iter().find(|s| s.stage == stage).map(|s| s.action)
+    }
+
+    #[test]
+    fn test_parse_installed() {
+        let installed =
+            parse_installed("driver=550.54.14\ndriver_package=\ncuda=\njq=0\nscripts=1\n");
+        assert_eq!(installed.driver.as_deref(), Some("550.54.14"));
+        assert_eq!(installed.driver_package, None);
+        assert_eq!(installed.cuda, None);
+        assert!(!installed.jq);
+        assert!(installed.scripts);
+        assert!(!installed.booted_since_driver);
+        assert_eq!(parse_installed(""), Installed::default());
+
+        let installed = parse_installed("driver_installed=1700000000\nbooted=1700000300\n");
+        assert!(installed.booted_since_driver);
+        let installed = parse_installed("driver_installed=1700000300\nbooted=1700000000\n");
+        assert!(!installed.booted_since_driver);
+    }
+
+    #[test]
+    fn test_plan_deploy() {
+        let mut config = ProverConfig {
+            version: "0.2.3".to_owned(),
+            address: "aleo1abc".to_owned(),
+            sha256: None,
+        };
+        // deployed and running, a re-run changes nothing
+        let steps = plan_deploy(&installed(), &config);
+        assert_eq!(steps.len(), 12);
+        assert_eq!(steps[0].stage, DeployStage::Inspecting);
+        for stage in [
+            DeployStage::InstallingDriver,
+            DeployStage::InstallingCuda,
+            DeployStage::DownloadingProver,
+            DeployStage::EnablingService,
+            DeployStage::RestartingProver,
+            DeployStage::Rebooting,
+        ] {
+            assert_eq!(action(&steps, stage), Some(Action::Keep), "{:?}", stage);
+        }
+
+        // a new prover is restarted without a reboot
+        config.version = "0.2.4".to_owned();
+        let steps = plan_deploy(&installed(), &config);
+        assert_eq!(
+            action(&steps, DeployStage::DownloadingProver),
+            Some(Action::Upgrade)
+        );
+        assert_eq!(
+            action(&steps, DeployStage::RestartingProver),
+            Some(Action::Run)
+        );
+        assert_eq!(action(&steps, DeployStage::Rebooting), Some(Action::Keep));
+
+        // driver installed before the host rebooted
+        let pending = Installed {
+            driver: None,
+            ..installed()
+        };
+        let steps = plan_deploy(&pending, &config);
+        assert_eq!(
+            action(&steps, DeployStage::InstallingDriver),
+            Some(Action::Keep)
+        );
+        assert_eq!(action(&steps, DeployStage::Rebooting), Some(Action::Run));
+
+        // and still not loaded after that reboot, another one won't help
+        let broken = Installed {
+            booted_since_driver: true,
+            ..pending
+        };
+        let steps = plan_deploy(&broken, &config);
+        assert_eq!(
+            action(&steps, DeployStage::InstallingDriver),
+            Some(Action::Blocked)
+        );
+        assert!(!HostPlan::default().with_steps(steps).ready);
+
+        let steps = plan_deploy(&Installed::default(), &config);
+        assert_eq!(
+            action(&steps, DeployStage::InstallingCuda),
+            Some(Action::Install)
+        );
+        assert_eq!(
+            action(&steps, DeployStage::RestartingProver),
+            Some(Action::Keep)
+        );
+        assert_eq!(
+            action(&steps, DeployStage::VerifyingHashrate),
+            Some(Action::Run)
+        );
+    }
+
+    #[test]
+    fn test_plan_update() {
+        let update = ProverUpdate {
+            version: None,
+            address: Some("aleo1xyz".to_owned()),
+            sha256: None,
+        };
+        let steps = plan_update(&installed(), &update);
+        assert_eq!(
+            action(&steps, DeployStage::DownloadingProver),
+            Some(Action::Keep)
+        );
+        assert_eq!(
+            action(&steps, DeployStage::EnablingService),
+            Some(Action::Change)
+        );
+        let plan = HostPlan::default().with_steps(steps);
+        assert!(plan.ready);
+
+        // never deployed by the agent
+        let plan = HostPlan::default().with_steps(plan_update(&Installed::default(), &update));
+        assert!(!plan.ready);
+        assert_eq!(plan.steps[0].action, Action::Blocked);
+
+        // a stopped prover keeps the address of its start script
+        let stopped = Installed {
+            prover: RunningProver {
+                version: Some("0.2.3".to_owned()),
+                ..RunningProver::default()
+            },
+            prover_running: false,
+            configured_address: Some("aleo1abc".to_owned()),
+            ..installed()
+        };
+        let update = ProverUpdate {
+            version: Some("0.2.4".to_owned()),
+            address: None,
+            sha256: None,
+        };
+        let plan = HostPlan::default().with_steps(plan_update(&stopped, &update));
+        assert!(plan.ready);
+        let plan = HostPlan::default().with_steps(plan_update(
+            &Installed {
+                configured_address: None,
+                ..stopped
+            },
+            &update,
+        ));
+        assert!(!plan.ready);
+    }
+
+    #[test]
+    fn test_plan_deploy_stopped_prover() {
+        let config = ProverConfig {
+            version: "0.2.3".to_owned(),
+            address: "aleo1abc".to_owned(),
+            sha256: None,
+        };
+        let stopped = Installed {
+            prover: RunningProver {
+                version: Some("0.2.3".to_owned()),
+                ..RunningProver::default()
+            },
+            prover_running: false,
+            configured_address: Some("aleo1abc".to_owned()),
+            ..installed()
+        };
+        // only started again
+        let steps = plan_deploy(&stopped, &config);
+        assert_eq!(
+            action(&steps, DeployStage::EnablingService),
+            Some(Action::Keep)
+        );
+        assert_eq!(
+            action(&steps, DeployStage::RestartingProver),
+            Some(Action::Run)
+        );
+
+        let other = Installed {
+            configured_address: Some("aleo1old".to_owned()),
+            ..stopped
+        };
+        let steps = plan_deploy(&other, &config);
+        assert_eq!(
+            action(&steps, DeployStage::EnablingService),
+            Some(Action::Change)
+        );
+    }
+
+    #[test]
+    fn test_plan_deploy_driver_without_dpkg() {
+        let config = ProverConfig {
+            version: "0.2.3".to_owned(),
+            address: "aleo1abc".to_owned(),
+            sha256: None,
+        };
+        for output in [
+            "driver=550.54.14\ndriver_module=550.54.14\ndriver_package=\n",
+            // nvidia-smi fails, the module is loaded
+            "driver=\ndriver_module=550.54.14\ndriver_package=\n",
+        ] {
+            let installed = Installed {
+                driver_package: None,
+                ..installed()
+            };
+            let installed = Installed {
+                driver: parse_installed(output).driver,
+                driver_module: parse_installed(output).driver_module,
+                ..installed
+            };
+            let steps = plan_deploy(&installed, &config);
+            assert_eq!(
+                action(&steps, DeployStage::InstallingDriver),
+                Some(Action::Keep),
+                "{}",
+                output
+            );
+            assert_eq!(action(&steps, DeployStage::Rebooting), Some(Action::Keep));
+        }
+    }
+}
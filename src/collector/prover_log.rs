@@ -0,0 +1,278 @@
+// hashrate tables of the zkwork prover log
+//
+// The prover prints a table every minute, a timestamp row followed by a row per gpu
+// and a `gpu[*]` row with the sum:
+//
+//   | 2024-10-13T10:22:57 |
+//   | gpu[0]: (1m - 445925 5m - 445325 15m - 332917 30m - 306742 60m - 309270) |
+//   | gpu[*]: (1m - 445925 5m - 445325 15m - 332917 30m - 306742 60m - 309270) |
+//
+// Color codes, `1m: 512.4` pairs and timestamps with fractions or a space instead
+// of the T are read as well. A table is complete with its `gpu[*]` row, the last
+// complete one wins. A table with a row that can't be read is dropped, so is one
+// cut off by the end of the log.
+
+use chrono::NaiveDateTime;
+
+use crate::collector::metrics::{GpuSlot, ProverInfo};
+use crate::error::AgentError;
+
+pub const PROVER_LOG: &str = "/opt/aleo_prover/prover.log";
+// lines fetched from the end of the log, a table of 8 gpus is about 12 lines
+pub const PROVER_LOG_LINES: usize = 200;
+
+const WINDOWS: [&str; 5] = ["1m", "5m", "15m", "30m", "60m"];
+
+const TIMESTAMP_FORMATS: [&str; 2] = ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"];
+
+// the latest complete hashrate table of a log tail
+pub fn parse_prover_log(text: &str) -> Result<Vec<ProverInfo>, AgentError> {
+    let mut latest = vec![];
+    // timestamp and rows of the table being read, None for the rows once one is bad
+    let mut table: Option<(NaiveDateTime, Option<Vec<ProverInfo>>)> = None;
+    let mut gpu_lines = 0;
+    let mut bad_row = None;
+
+    for line in text.lines() {
+        let line = strip_ansi(line);
+        let Some(cell) = first_cell(&line) else {
+            continue;
+        };
+
+        if let Some(timestamp) = parse_timestamp(cell) {
+            table = Some((timestamp, Some(vec![])));
+        } else if cell.starts_with("gpu[") {
+            gpu_lines += 1;
+            // rows before the first timestamp belong to a table cut off by the tail
+            if let Some((timestamp, table_rows)) = &mut table {
+                let Some(rows) = table_rows else {
+                    continue;
+                };
+                match parse_row(*timestamp, cell) {
+                    // the sum row closes the table, rows after it belong to none
+                    Ok(row) if row.gpu_index == GpuSlot::Total => {
+                        rows.push(row);
+                        latest = std::mem::take(rows);
+                        *table_rows = None;
+                    }
+                    Ok(row) => rows.push(row),
+                    Err(e) => {
+                        *table_rows = None;
+                        bad_row = Some(e);
+                    }
+                }
+            }
+        }
+    }
+
+    if latest.is_empty() {
+        if let Some(e) = bad_row {
+            return Err(e);
+        }
+        return Err(AgentError::ParseError(if gpu_lines == 0 {
+            "no hashrate table in prover log".to_owned()
+        } else {
+            "no complete hashrate table in prover log".to_owned()
+        }));
+    }
+    Ok(latest)
+}
+
+// drop terminal color codes such as \x1b[36m
+fn strip_ansi(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // skip up to and including the final letter of the sequence
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+// content of the first cell of a table row, None for other log lines
+fn first_cell(line: &str) -> Option<&str> {
+    let rest = line.trim().strip_prefix('|')?;
+    let cell = match rest.find(" |") {
+        Some(end) => &rest[..end],
+        None => rest.trim_end_matches('|'),
+    };
+    let cell = cell.trim();
+    (!cell.is_empty()).then_some(cell)
+}
+
+fn parse_timestamp(cell: &str) -> Option<NaiveDateTime> {
+    TIMESTAMP_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(cell, format).ok())
+}
+
+// gpu[0]: (1m - 445925 5m - 445325 ...), the windows may come in any order
+fn parse_row(timestamp: NaiveDateTime, cell: &str) -> Result<ProverInfo, AgentError> {
+    let invalid = |reason: &str| {
+        AgentError::ParseError(format!("unrecognized hashrate row {:?}: {}", cell, reason))
+    };
+
+    let (slot, rest) = cell["gpu[".len()..]
+        .split_once(']')
+        .ok_or_else(|| invalid("unterminated gpu index"))?;
+    let gpu_index = match slot.trim() {
+        "*" => GpuSlot::Total,
+        index => GpuSlot::Gpu(index.parse().map_err(|_| invalid("bad gpu index"))?),
+    };
+
+    let text: String = rest
+        .chars()
+        .map(|c| if "():-=,".contains(c) { ' ' } else { c })
+        .collect();
+    let tokens: Vec<&str> = text.split_whitespace().collect();
+
+    let mut rates = [None; 5];
+    for pair in tokens.windows(2) {
+        if let Some(i) = WINDOWS.iter().position(|w| *w == pair[0]) {
+            let rate: f64 = pair[1].parse().map_err(|_| invalid("bad hashrate"))?;
+            rates[i] = Some(rate.round() as u64);
+        }
+    }
+    let rate = |i: usize| rates[i].ok_or_else(|| invalid(&format!("no {} hashrate", WINDOWS[i])));
+
+    Ok(ProverInfo {
+        timestamp,
+        gpu_index,
+        one_min: rate(0)?,
+        five_min: rate(1)?,
+        fifteen_min: rate(2)?,
+        thirty_min: rate(3)?,
+        sixty_min: rate(4)?,
+    })
+}
+
+// test
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_table() {
+        let rows = parse_prover_log(include_str!("fixtures/prover_0.2.log")).unwrap();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0].timestamp.to_string(), "2024-10-13 10:22:57");
+        assert_eq!(rows[0].gpu_index, GpuSlot::Gpu(0));
+        assert_eq!(rows[0].one_min, 445925);
+        assert_eq!(rows[0].sixty_min, 309270);
+        assert_eq!(rows[2].gpu_index, GpuSlot::Total);
+        assert_eq!(rows[2].five_min, 890425);
+    }
+
+    #[test]
+    fn test_parse_skips_bad_table() {
+        let good = |minute: u32| {
+            format!(
+                "| 2024-10-13T10:{}:57 |\n| gpu[*]: (1m - {} 5m - 1 15m - 1 30m - 1 60m - 1) |\n",
+                minute, minute
+            )
+        };
+        let bad = "| 2024-10-13T10:24:57 |\n| gpu[0]: (1m - 1 5m - x) |\n";
+
+        // a bad table after a complete one keeps the complete one
+        let rows = parse_prover_log(&(good(22) + bad)).unwrap();
+        assert_eq!(rows[0].one_min, 22);
+
+        // a complete table after a bad one wins
+        let rows = parse_prover_log(&(bad.to_owned() + &good(25))).unwrap();
+        assert_eq!(rows[0].one_min, 25);
+    }
+
+    #[test]
+    fn test_parse_truncated_tail() {
+        // the leading orphan row and the trailing empty table are skipped
+        let rows = parse_prover_log(include_str!("fixtures/prover_truncated.log")).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].timestamp.to_string(), "2024-10-13 10:23:57");
+    }
+
+    #[test]
+    fn test_parse_cut_off_table() {
+        let log = "| 2024-10-13T10:22:57 |\n\
+                   | gpu[0]: (1m - 22 5m - 1 15m - 1 30m - 1 60m - 1) |\n\
+                   | gpu[*]: (1m - 22 5m - 1 15m - 1 30m - 1 60m - 1) |\n\
+                   | 2024-10-13T10:23:57 |\n\
+                   | gpu[0]: (1m - 23 5m - 1 15m - 1 30m - 1 60m - 1) |\n";
+        // the tail ends before the gpu[*] row of the second table
+        let rows = parse_prover_log(log).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].one_min, 22);
+
+        let err = parse_prover_log(
+            "| 2024-10-13T10:23:57 |\n| gpu[0]: (1m - 1 5m - 1 15m - 1 30m - 1 60m - 1) |",
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("no complete"), "{}", err);
+    }
+
+    #[test]
+    fn test_parse_colors() {
+        let log = "\x1b[36m| 2024-10-13T10:22:57 |\x1b[0m\n\
+                   | \x1b[32mgpu[*]\x1b[0m: (1m - 7 5m - 1 15m - 1 30m - 1 60m - 1) |\n";
+        let rows = parse_prover_log(log).unwrap();
+        assert_eq!(rows[0].gpu_index, GpuSlot::Total);
+        assert_eq!(rows[0].one_min, 7);
+        assert_eq!(strip_ansi("\x1b[1;31mred\x1b[0m"), "red");
+    }
+
+    #[test]
+    fn test_parse_colon_pairs() {
+        let log = "| 2024-10-13T10:22:57 |\n\
+                   | gpu[*]: 60m: 5.5, 1m: 512.4, 5m: 1, 15m: 2, 30m: 3 |\n";
+        let rows = parse_prover_log(log).unwrap();
+        assert_eq!(rows[0].one_min, 512);
+        assert_eq!(rows[0].sixty_min, 6);
+        assert_eq!(rows[0].fifteen_min, 2);
+    }
+
+    #[test]
+    fn test_parse_timestamp_formats() {
+        let parsed = |cell: &str| parse_timestamp(cell).map(|t| t.to_string());
+        assert_eq!(
+            parsed("2024-10-13T10:22:57.412").as_deref(),
+            Some("2024-10-13 10:22:57.412")
+        );
+        assert_eq!(
+            parsed("2024-10-13 10:22:57").as_deref(),
+            Some("2024-10-13 10:22:57")
+        );
+        assert_eq!(
+            parsed("2024-10-13 10:22:57.5").as_deref(),
+            Some("2024-10-13 10:22:57.500")
+        );
+        assert_eq!(parsed("gpu[0]"), None);
+    }
+
+    #[test]
+    fn test_parse_failures() {
+        let err = parse_prover_log(include_str!("fixtures/prover_unknown.log")).unwrap_err();
+        assert!(matches!(err, AgentError::ParseError(_)));
+        assert!(
+            err.to_string().contains("unrecognized hashrate row"),
+            "{}",
+            err
+        );
+
+        let err = parse_prover_log("").unwrap_err();
+        assert!(err.to_string().contains("no hashrate table"), "{}", err);
+
+        let err =
+            parse_prover_log("| gpu[0]: (1m - 1 5m - 1 15m - 1 30m - 1 60m - 1) |").unwrap_err();
+        assert!(err.to_string().contains("no complete"), "{}", err);
+
+        let err = parse_prover_log("| 2024-10-13T10:22:57 |\n| gpu[x]: (1m - 1) |").unwrap_err();
+        assert!(err.to_string().contains("bad gpu index"), "{}", err);
+    }
+}
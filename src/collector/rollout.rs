@@ -0,0 +1,160 @@
+// staged deploys
+//
+// A rollout deploys to a canary wave first, then to waves of `wave_size` hosts,
+// one wave after the other. Every host has to show a hashrate within
+// `verify_seconds` to count as deployed. A failing canary host, or a wave with a
+// failure rate above `max_failure_rate`, halts the rollout and the remaining
+// hosts are skipped.
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::AgentError;
+
+// deploys wait this long for the first hashrate once the prover runs,
+// it prints a table every minute but may need to reach the pool first
+pub const VERIFY_SECONDS: u64 = 1200;
+
+fn default_canary() -> usize {
+    1
+}
+
+fn default_wave_size() -> usize {
+    16
+}
+
+fn default_max_failure_rate() -> f64 {
+    0.2
+}
+
+fn default_verify_seconds() -> u64 {
+    VERIFY_SECONDS
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RolloutPlan {
+    // hosts deployed and verified before any other, 0 skips the canary
+    #[serde(default = "default_canary")]
+    pub canary: usize,
+    #[serde(default = "default_wave_size")]
+    pub wave_size: usize,
+    // failed hosts over hosts of a wave, above it the rollout halts
+    #[serde(default = "default_max_failure_rate")]
+    pub max_failure_rate: f64,
+    // deadline for the hashrate to show up, see deploy_to_ip
+    #[serde(default = "default_verify_seconds")]
+    pub verify_seconds: u64,
+}
+
+// outcome of one wave
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct WaveDone {
+    pub wave: usize,
+    pub canary: bool,
+    pub hosts: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+}
+
+impl RolloutPlan {
+    pub fn validate(&self) -> Result<(), AgentError> {
+        if self.wave_size == 0 {
+            return Err(AgentError::ProtocolError(
+                "rollout wave_size must be at least 1".to_owned(),
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.max_failure_rate) {
+            return Err(AgentError::ProtocolError(format!(
+                "rollout max_failure_rate must be between 0 and 1, got {}",
+                self.max_failure_rate
+            )));
+        }
+        if self.verify_seconds == 0 {
+            return Err(AgentError::ProtocolError(
+                "rollout verify_seconds must be at least 1".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    // the canary wave if any, then the rest in waves of wave_size
+    pub fn waves(&self, ips: &[String]) -> Vec<Vec<String>> {
+        let canary = self.canary.min(ips.len());
+        let mut waves = vec![];
+        if canary > 0 {
+            waves.push(ips[..canary].to_vec());
+        }
+        waves.extend(
+            ips[canary..]
+                .chunks(self.wave_size.max(1))
+                .map(|wave| wave.to_vec()),
+        );
+        waves
+    }
+
+    // true when the rollout must not go on after this wave
+    pub fn halts(&self, wave: &WaveDone) -> bool {
+        if wave.canary {
+            return wave.failed > 0;
+        }
+        wave.hosts > 0 && wave.failed as f64 / wave.hosts as f64 > self.max_failure_rate
+    }
+}
+
+// test
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(canary: usize, wave_size: usize) -> RolloutPlan {
+        RolloutPlan {
+            canary,
+            wave_size,
+            max_failure_rate: 0.2,
+            verify_seconds: VERIFY_SECONDS,
+        }
+    }
+
+    fn ips(n: usize) -> Vec<String> {
+        (1..=n).map(|i| format!("10.0.0.{}", i)).collect()
+    }
+
+    #[test]
+    fn test_waves() {
+        let sizes = |waves: Vec<Vec<String>>| waves.iter().map(|w| w.len()).collect::<Vec<_>>();
+        assert_eq!(sizes(plan(2, 4).waves(&ips(11))), vec![2, 4, 4, 1]);
+        assert_eq!(sizes(plan(0, 4).waves(&ips(8))), vec![4, 4]);
+        assert_eq!(sizes(plan(5, 4).waves(&ips(3))), vec![3]);
+        assert!(plan(1, 4).waves(&[]).is_empty());
+        assert_eq!(plan(2, 4).waves(&ips(3))[1], vec!["10.0.0.3"]);
+    }
+
+    #[test]
+    fn test_halts() {
+        let wave = |canary, hosts, failed| WaveDone {
+            wave: 0,
+            canary,
+            hosts,
+            succeeded: hosts - failed,
+            failed,
+        };
+        let plan = plan(1, 10);
+        assert!(plan.halts(&wave(true, 2, 1)));
+        assert!(!plan.halts(&wave(true, 2, 0)));
+        assert!(!plan.halts(&wave(false, 10, 2)));
+        assert!(plan.halts(&wave(false, 10, 3)));
+    }
+
+    #[test]
+    fn test_validate() {
+        let parsed: RolloutPlan = serde_json::from_str(r#"{"wave_size":8}"#).unwrap();
+        assert_eq!(parsed.canary, 1);
+        assert_eq!(parsed.verify_seconds, VERIFY_SECONDS);
+        assert!(parsed.validate().is_ok());
+
+        assert!(plan(1, 0).validate().is_err());
+        let mut bad = plan(1, 4);
+        bad.max_failure_rate = 1.5;
+        assert!(bad.validate().is_err());
+    }
+}
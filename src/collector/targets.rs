@@ -0,0 +1,211 @@
+// target specifications for fleet commands
+//
+// Each entry is one of
+//   192.168.1.7                  single address
+//   192.168.1.0/24               cidr, network and broadcast addresses are skipped
+//   192.168.1.10-192.168.1.20    inclusive range
+//   192.168.1.10-20              inclusive range within the last octet
+// Excludes use the same syntax. The result is sorted and deduplicated.
+
+use std::collections::BTreeSet;
+use std::net::Ipv4Addr;
+
+use ipnet::Ipv4Net;
+use serde::Deserialize;
+
+use crate::error::AgentError;
+
+// refuse specs that would expand to more hosts than a site has, e.g. a /8
+pub const MAX_TARGETS: usize = 65536;
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetSpec {
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+impl TargetSpec {
+    pub fn new(include: &[&str]) -> Self {
+        TargetSpec {
+            include: include.iter().map(|s| s.to_string()).collect(),
+            exclude: vec![],
+        }
+    }
+
+    // .1 to .255 of the /24 the ip belongs to, what a bare `ip` used to mean
+    pub fn subnet(ip: Ipv4Addr) -> Self {
+        let [a, b, c, _] = ip.octets();
+        TargetSpec::new(&[&format!("{}.{}.{}.1-255", a, b, c)])
+    }
+
+    // all addresses of the spec, in ascending order
+    pub fn expand(&self) -> Result<Vec<Ipv4Addr>, AgentError> {
+        let mut hosts = BTreeSet::new();
+        for entry in &self.include {
+            let (start, end) = parse_entry(entry)?;
+            // addresses listed twice count once
+            let size = (end - start) as usize + 1;
+            let known = hosts
+                .range(Ipv4Addr::from(start)..=Ipv4Addr::from(end))
+                .count();
+            if hosts.len() + size - known > MAX_TARGETS {
+                return Err(invalid(
+                    entry,
+                    &format!("more than {} targets", MAX_TARGETS),
+                ));
+            }
+            hosts.extend((start..=end).map(Ipv4Addr::from));
+        }
+
+        for entry in &self.exclude {
+            let (start, end) = parse_entry(entry)?;
+            hosts.retain(|ip| !(start..=end).contains(&u32::from(*ip)));
+        }
+
+        Ok(hosts.into_iter().collect())
+    }
+
+    pub fn validate(&self) -> Result<(), AgentError> {
+        if self.expand()?.is_empty() {
+            return Err(AgentError::ProtocolError("targets are empty".to_owned()));
+        }
+        Ok(())
+    }
+
+    // expanded addresses as strings, the form batch operations take
+    pub fn ips(&self) -> Result<Vec<String>, AgentError> {
+        Ok(self.expand()?.iter().map(|ip| ip.to_string()).collect())
+    }
+}
+
+fn invalid(entry: &str, reason: &str) -> AgentError {
+    AgentError::ProtocolError(format!("invalid target {:?}: {}", entry, reason))
+}
+
+fn parse_ip(entry: &str, s: &str) -> Result<Ipv4Addr, AgentError> {
+    s.trim()
+        .parse::<Ipv4Addr>()
+        .map_err(|_| invalid(entry, "not an ipv4 address"))
+}
+
+// inclusive range of the entry as u32 addresses
+fn parse_entry(entry: &str) -> Result<(u32, u32), AgentError> {
+    let trimmed = entry.trim();
+
+    if trimmed.contains('/') {
+        let net = trimmed
+            .parse::<Ipv4Net>()
+            .map_err(|_| invalid(entry, "not a cidr"))?;
+        // hosts() skips network and broadcast addresses for prefixes up to /30
+        let mut hosts = net.hosts();
+        let start = hosts.next().ok_or_else(|| invalid(entry, "no hosts"))?;
+        let end = hosts.last().unwrap_or(start);
+        return Ok((u32::from(start), u32::from(end)));
+    }
+
+    if let Some((from, to)) = trimmed.split_once('-') {
+        let start = parse_ip(entry, from)?;
+        let end = if to.contains('.') {
+            parse_ip(entry, to)?
+        } else {
+            let last = to
+                .trim()
+                .parse::<u8>()
+                .map_err(|_| invalid(entry, "range end is not an octet"))?;
+            let [a, b, c, _] = start.octets();
+            Ipv4Addr::new(a, b, c, last)
+        };
+        if start > end {
+            return Err(invalid(entry, "range end is before its start"));
+        }
+        return Ok((u32::from(start), u32::from(end)));
+    }
+
+    let ip = u32::from(parse_ip(entry, trimmed)?);
+    Ok((ip, ip))
+}
+
+// test
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(include: &[&str], exclude: &[&str]) -> Result<Vec<String>, AgentError> {
+        TargetSpec {
+            include: include.iter().map(|s| s.to_string()).collect(),
+            exclude: exclude.iter().map(|s| s.to_string()).collect(),
+        }
+        .ips()
+    }
+
+    #[test]
+    fn test_cidr() {
+        let ips = expand(&["10.0.0.0/30"], &[]).unwrap();
+        assert_eq!(ips, vec!["10.0.0.1", "10.0.0.2"]);
+
+        let ips = expand(&["10.0.0.0/23"], &[]).unwrap();
+        assert_eq!(ips.len(), 510);
+        assert_eq!(ips[0], "10.0.0.1");
+        assert_eq!(ips[509], "10.0.1.254");
+
+        assert_eq!(expand(&["10.0.0.9/32"], &[]).unwrap(), vec!["10.0.0.9"]);
+        // host bits set are fine, the network is what counts
+        assert_eq!(expand(&["10.0.0.77/30"], &[]).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_ranges_and_lists() {
+        let ips = expand(&["10.0.0.250-10.0.1.2"], &[]).unwrap();
+        assert_eq!(ips.len(), 9);
+
+        let ips = expand(&["10.0.0.5-7", "10.0.0.6", "10.0.0.1"], &[]).unwrap();
+        assert_eq!(ips, vec!["10.0.0.1", "10.0.0.5", "10.0.0.6", "10.0.0.7"]);
+
+        let ips = TargetSpec::subnet("10.0.3.77".parse().unwrap())
+            .ips()
+            .unwrap();
+        assert_eq!(ips.len(), 255);
+        assert_eq!(ips[254], "10.0.3.255");
+    }
+
+    #[test]
+    fn test_exclude() {
+        let ips = expand(&["10.0.0.0/24"], &["10.0.0.1", "10.0.0.10-254"]).unwrap();
+        assert_eq!(ips.len(), 8);
+        assert_eq!(ips[0], "10.0.0.2");
+        assert_eq!(ips[7], "10.0.0.9");
+
+        let spec = TargetSpec {
+            include: vec!["10.0.0.1".to_owned()],
+            exclude: vec!["10.0.0.0/24".to_owned()],
+        };
+        assert!(spec.validate().is_err());
+    }
+
+    #[test]
+    fn test_max_targets() {
+        let ips = expand(&["10.0.0.0-10.0.255.255"], &[]).unwrap();
+        assert_eq!(ips.len(), MAX_TARGETS);
+        assert!(expand(&["10.0.0.0-10.1.0.0"], &[]).is_err());
+
+        // duplicates don't count against the limit
+        let ips = expand(&["10.0.0.0/16", "10.0.0.0/16"], &[]).unwrap();
+        assert_eq!(ips.len(), 65534);
+        let ips = expand(&["10.0.0.0-10.0.255.255", "10.0.0.7"], &[]).unwrap();
+        assert_eq!(ips.len(), MAX_TARGETS);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(expand(&["10.0.0.0/33"], &[]).is_err());
+        assert!(expand(&["10.0.0.300"], &[]).is_err());
+        assert!(expand(&["10.0.0.9-3"], &[]).is_err());
+        assert!(expand(&["10.0.0.1-300"], &[]).is_err());
+        assert!(expand(&["host.local"], &[]).is_err());
+        assert!(expand(&["10.0.0.0/8"], &[]).is_err());
+        assert!(expand(&["10.0.0.0/16", "10.1.0.0/29"], &[]).is_err());
+        assert!(expand(&["10.0.0.1"], &["nope"]).is_err());
+    }
+}
@@ -1,11 +1,31 @@
 use thiserror::Error;
 
+use crate::collector::BatchReport;
+use crate::known_hosts::HostKeyChange;
+
+#[allow(clippy::enum_variant_names)]
 #[derive(Error, Debug)]
 pub enum AgentError {
     #[error("WebSocket error: {0}")]
     WebSocketError(String),
     #[error("Command error: {0}")]
     CommandError(String),
+    #[error("SSH error: {0}")]
+    SshError(String),
+    #[error("Authentication failed: {0}")]
+    AuthError(String),
+    #[error("Host key changed for {}:{}, expected {}, got {}", .0.host, .0.port, .0.expected, .0.actual)]
+    HostKeyChanged(HostKeyChange),
+    #[error("Timeout after {0} seconds")]
+    Timeout(u64),
+    #[error("Protocol error: {0}")]
+    ProtocolError(String),
+    #[error("Parse error: {0}")]
+    ParseError(String),
+    #[error("Checksum mismatch for {0}, expected {1}, got {2}")]
+    ChecksumMismatch(String, String, String),
+    #[error("Cancelled")]
+    Cancelled(BatchReport),
     //Utf8Error
     #[error(transparent)]
     Utf8Error(#[from] std::str::Utf8Error),
@@ -16,3 +36,21 @@ pub enum AgentError {
     #[error(transparent)]
     SerdeJsonError(#[from] serde_json::Error),
 }
+
+impl From<russh::Error> for AgentError {
+    fn from(e: russh::Error) -> Self {
+        AgentError::SshError(e.to_string())
+    }
+}
+
+impl From<russh_sftp::client::error::Error> for AgentError {
+    fn from(e: russh_sftp::client::error::Error) -> Self {
+        AgentError::SshError(e.to_string())
+    }
+}
+
+impl From<russh::keys::Error> for AgentError {
+    fn from(e: russh::keys::Error) -> Self {
+        AgentError::SshError(e.to_string())
+    }
+}
@@ -0,0 +1,356 @@
+// trust-on-first-use store of ssh host keys, kept in ~/.lcd-agent/known_hosts.json
+//
+// The first key a host presents is trusted and saved. A different key later is
+// rejected with AgentError::HostKeyChanged and announced to subscribers until the
+// server approves it. Keys pinned by the server can only be replaced by pinning again.
+// Without a loaded store every host key is rejected, a store that can't be read must
+// not turn into trusting every host again.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use log::{error, info};
+use russh::keys::{HashAlg, PublicKey};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::error::AgentError;
+
+const KNOWN_HOSTS_FILE: &str = "known_hosts.json";
+
+lazy_static! {
+    static ref KNOWN_HOSTS: Mutex<Option<KnownHosts>> = Mutex::new(None);
+    static ref CHANGES: broadcast::Sender<HostKeyChange> = broadcast::channel(64).0;
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HostKey {
+    // openssh encoded public key, e.g. "ssh-ed25519 AAAA..."
+    pub key: String,
+    #[serde(default)]
+    pub pinned: bool,
+}
+
+impl HostKey {
+    pub fn fingerprint(&self) -> Result<String, AgentError> {
+        Ok(fingerprint(&parse_key(&self.key)?))
+    }
+}
+
+// a host presented a key that doesn't match the stored one
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HostKeyChange {
+    pub host: String,
+    pub port: u16,
+    // sha256 fingerprints of the stored and the presented key
+    pub expected: String,
+    pub actual: String,
+}
+
+#[derive(Debug, Default)]
+pub struct KnownHosts {
+    // not persisted when None
+    path: Option<PathBuf>,
+    keys: HashMap<String, HostKey>,
+    // keys presented by hosts that didn't match, waiting for approval
+    pending: HashMap<String, (PublicKey, HostKeyChange)>,
+}
+
+fn host_id(host: &str, port: u16) -> String {
+    format!("{}:{}", host, port)
+}
+
+pub fn fingerprint(key: &PublicKey) -> String {
+    key.fingerprint(HashAlg::Sha256).to_string()
+}
+
+fn parse_key(key: &str) -> Result<PublicKey, AgentError> {
+    PublicKey::from_openssh(key.trim())
+        .map_err(|e| AgentError::ProtocolError(format!("invalid host key: {}", e)))
+}
+
+fn encode_key(key: &PublicKey) -> Result<String, AgentError> {
+    // host keys don't carry comments, drop them so equal keys encode equally
+    let mut key = key.clone();
+    key.set_comment("");
+    key.to_openssh()
+        .map_err(|e| AgentError::SshError(e.to_string()))
+}
+
+impl KnownHosts {
+    pub fn load(path: &Path) -> Result<Self, AgentError> {
+        let keys = if path.exists() {
+            serde_json::from_str(&fs::read_to_string(path)?)?
+        } else {
+            HashMap::new()
+        };
+
+        Ok(KnownHosts {
+            path: Some(path.to_owned()),
+            keys,
+            pending: HashMap::new(),
+        })
+    }
+
+    // through a temp file, a crash while writing leaves the old store in place
+    fn save(&self) -> Result<(), AgentError> {
+        if let Some(path) = &self.path {
+            let tmp = path.with_extension("json.tmp");
+            fs::write(&tmp, serde_json::to_string_pretty(&self.keys)?)?;
+            fs::rename(&tmp, path)?;
+        }
+        Ok(())
+    }
+
+    // changes still waiting for approval
+    pub fn pending_changes(&self) -> Vec<HostKeyChange> {
+        self.pending
+            .values()
+            .map(|(_, change)| change.clone())
+            .collect()
+    }
+
+    // Ok(None) when the key is trusted, Ok(Some(change)) when it doesn't match
+    pub fn check(
+        &mut self,
+        host: &str,
+        port: u16,
+        key: &PublicKey,
+    ) -> Result<Option<HostKeyChange>, AgentError> {
+        let id = host_id(host, port);
+        let known = match self.keys.get(&id) {
+            Some(known) => parse_key(&known.key)?,
+            None => {
+                info!("trust new host key {} {}", id, fingerprint(key));
+                self.keys.insert(
+                    id,
+                    HostKey {
+                        key: encode_key(key)?,
+                        pinned: false,
+                    },
+                );
+                self.save()?;
+                return Ok(None);
+            }
+        };
+
+        if known.key_data() == key.key_data() {
+            return Ok(None);
+        }
+
+        let change = HostKeyChange {
+            host: host.to_owned(),
+            port,
+            expected: fingerprint(&known),
+            actual: fingerprint(key),
+        };
+        self.pending.insert(id, (key.clone(), change.clone()));
+        Ok(Some(change))
+    }
+
+    // trust the key the host presented last, fingerprint must match it
+    pub fn approve(
+        &mut self,
+        host: &str,
+        port: u16,
+        key_fingerprint: &str,
+    ) -> Result<HostKey, AgentError> {
+        let id = host_id(host, port);
+        if self.keys.get(&id).is_some_and(|known| known.pinned) {
+            return Err(AgentError::ProtocolError(format!(
+                "host key of {} is pinned, pin the new key instead",
+                id
+            )));
+        }
+
+        let key = match self.pending.get(&id) {
+            Some((key, _)) if fingerprint(key) == key_fingerprint => key.clone(),
+            Some((key, _)) => {
+                return Err(AgentError::ProtocolError(format!(
+                    "fingerprint mismatch for {}, host presented {}",
+                    id,
+                    fingerprint(key)
+                )))
+            }
+            None => {
+                return Err(AgentError::ProtocolError(format!(
+                    "no changed host key waiting for approval for {}",
+                    id
+                )))
+            }
+        };
+
+        let entry = HostKey {
+            key: encode_key(&key)?,
+            pinned: false,
+        };
+        self.keys.insert(id.clone(), entry.clone());
+        self.pending.remove(&id);
+        self.save()?;
+        info!("approved host key {} {}", id, key_fingerprint);
+        Ok(entry)
+    }
+
+    // trust exactly this key for the host, replacing whatever was stored
+    pub fn pin(&mut self, host: &str, port: u16, key: &str) -> Result<HostKey, AgentError> {
+        let id = host_id(host, port);
+        let key = parse_key(key)?;
+        let entry = HostKey {
+            key: encode_key(&key)?,
+            pinned: true,
+        };
+        self.keys.insert(id.clone(), entry.clone());
+        self.pending.remove(&id);
+        self.save()?;
+        info!("pinned host key {} {}", id, fingerprint(&key));
+        Ok(entry)
+    }
+}
+
+// load the store from the agent home dir
+pub fn init(app_path: &str) -> Result<(), AgentError> {
+    let path = Path::new(app_path).join(KNOWN_HOSTS_FILE);
+    *KNOWN_HOSTS.lock().unwrap() = Some(KnownHosts::load(&path)?);
+    Ok(())
+}
+
+fn not_loaded() -> AgentError {
+    AgentError::SshError("known hosts store is not loaded".to_owned())
+}
+
+// verify the key a host presented, changes are announced once per new key
+pub fn check_host_key(host: &str, port: u16, key: &PublicKey) -> Result<(), AgentError> {
+    let mut known_hosts = KNOWN_HOSTS.lock().unwrap();
+    let known_hosts = known_hosts.as_mut().ok_or_else(not_loaded)?;
+    // pending changes are announced again to every new subscriber
+    let already_pending = known_hosts
+        .pending
+        .get(&host_id(host, port))
+        .is_some_and(|(pending, _)| pending.key_data() == key.key_data());
+
+    match known_hosts.check(host, port, key)? {
+        None => Ok(()),
+        Some(change) => {
+            error!(
+                "host key changed for {}:{}, expected {}, got {}",
+                host, port, change.expected, change.actual
+            );
+            if !already_pending {
+                // no receiver when the websocket is down
+                let _ = CHANGES.send(change.clone());
+            }
+            Err(AgentError::HostKeyChanged(change))
+        }
+    }
+}
+
+pub fn approve_host_key(
+    host: &str,
+    port: u16,
+    key_fingerprint: &str,
+) -> Result<HostKey, AgentError> {
+    KNOWN_HOSTS
+        .lock()
+        .unwrap()
+        .as_mut()
+        .ok_or_else(not_loaded)?
+        .approve(host, port, key_fingerprint)
+}
+
+pub fn pin_host_key(host: &str, port: u16, key: &str) -> Result<HostKey, AgentError> {
+    KNOWN_HOSTS
+        .lock()
+        .unwrap()
+        .as_mut()
+        .ok_or_else(not_loaded)?
+        .pin(host, port, key)
+}
+
+// changes waiting for approval and the ones detected from now on, a change
+// detected while nobody listened is in the former
+pub fn subscribe() -> (Vec<HostKeyChange>, broadcast::Receiver<HostKeyChange>) {
+    let known_hosts = KNOWN_HOSTS.lock().unwrap();
+    let pending = known_hosts
+        .as_ref()
+        .map(KnownHosts::pending_changes)
+        .unwrap_or_default();
+    (pending, CHANGES.subscribe())
+}
+
+// test
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_A: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIKu5wRUg3tzj98AuK4sMkmW/o24ksMQ53wwx/1eBqd46";
+    const KEY_B: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIJtpdJPVf5HOo3OouLTz4qx5YP0Fd6BD+0ing9ilx3Mu";
+
+    #[test]
+    fn test_trust_on_first_use() {
+        let mut known_hosts = KnownHosts::default();
+        let a = parse_key(KEY_A).unwrap();
+        let b = parse_key(KEY_B).unwrap();
+
+        assert_eq!(known_hosts.check("10.0.0.1", 22, &a).unwrap(), None);
+        assert_eq!(known_hosts.check("10.0.0.1", 22, &a).unwrap(), None);
+        // same host on another port is another entry
+        assert_eq!(known_hosts.check("10.0.0.1", 2222, &b).unwrap(), None);
+
+        let change = known_hosts.check("10.0.0.1", 22, &b).unwrap().unwrap();
+        assert_eq!(change.expected, fingerprint(&a));
+        assert_eq!(change.actual, fingerprint(&b));
+        assert_eq!(known_hosts.pending_changes(), vec![change]);
+
+        // approval must name the presented key
+        assert!(known_hosts
+            .approve("10.0.0.1", 22, &fingerprint(&a))
+            .is_err());
+        known_hosts
+            .approve("10.0.0.1", 22, &fingerprint(&b))
+            .unwrap();
+        assert_eq!(known_hosts.check("10.0.0.1", 22, &b).unwrap(), None);
+        assert!(known_hosts.check("10.0.0.1", 22, &a).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_pin() {
+        let mut known_hosts = KnownHosts::default();
+        let a = parse_key(KEY_A).unwrap();
+        let b = parse_key(KEY_B).unwrap();
+
+        let entry = known_hosts.pin("10.0.0.2", 22, KEY_A).unwrap();
+        assert!(entry.pinned);
+        assert_eq!(known_hosts.check("10.0.0.2", 22, &a).unwrap(), None);
+
+        // pinned keys can't be approved away
+        assert!(known_hosts.check("10.0.0.2", 22, &b).unwrap().is_some());
+        assert!(known_hosts
+            .approve("10.0.0.2", 22, &fingerprint(&b))
+            .is_err());
+
+        assert!(known_hosts.pin("10.0.0.2", 22, "ssh-ed25519 nope").is_err());
+    }
+
+    #[test]
+    fn test_load_and_save() {
+        let dir = std::env::temp_dir().join(format!("lcd-agent-hosts-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(KNOWN_HOSTS_FILE);
+
+        let mut known_hosts = KnownHosts::load(&path).unwrap();
+        known_hosts.pin("10.0.0.3", 22, KEY_A).unwrap();
+        assert!(!path.with_extension("json.tmp").exists());
+        let loaded = KnownHosts::load(&path).unwrap();
+        assert!(loaded.keys["10.0.0.3:22"].pinned);
+
+        // a broken store is an error, not an empty one
+        fs::write(&path, "{").unwrap();
+        assert!(KnownHosts::load(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
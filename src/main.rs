@@ -15,8 +15,12 @@ use tokio_cron_scheduler::{Job, JobScheduler, JobSchedulerError};
 use crate::tasks::watch_machines;
 use crate::ws::{connect_to_websocket, receive_message};
 
+mod artifacts;
 mod collector;
 mod error;
+mod known_hosts;
+mod protocol;
+mod prover;
 mod sh;
 mod tasks;
 mod ws;
@@ -84,11 +88,18 @@ async fn main() -> Result<(), JobSchedulerError> {
     // create home dir if not exist
     let app_path = create_home_dir().unwrap();
     init_log(&app_path);
+    // without the store host keys can't be checked, don't run at all
+    if let Err(e) = known_hosts::init(&app_path) {
+        error!("Failed to load known hosts: {}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = artifacts::init(&app_path) {
+        error!("Failed to create the artifact cache: {}", e);
+    }
     init_lcd(&app_path);
     let mut sched = JobScheduler::new().await?;
 
     let runtime = tokio::runtime::Builder::new_multi_thread()
-        .worker_threads(100)
         .enable_all()
         .build()
         .unwrap();
@@ -123,11 +134,10 @@ async fn main() -> Result<(), JobSchedulerError> {
         //let url = "ws://localhost:8080/websocket/a5b913409b4154b869869ea6d5d73e88";
         loop {
             info!("try to connect to websocket server");
-            let mut stream;
-            match connect_to_websocket(&url).await {
+            let stream = match connect_to_websocket(&url).await {
                 Ok(ws_stream) => {
                     info!("WebSocket handshake has been successfully completed");
-                    stream = ws_stream;
+                    ws_stream
                 }
                 Err(e) => {
                     error!("Failed to connect: {}", e);
@@ -135,9 +145,9 @@ async fn main() -> Result<(), JobSchedulerError> {
                     tokio::time::sleep(Duration::from_secs(10)).await;
                     continue;
                 }
-            }
+            };
 
-            receive_message(&mut stream, &rt_handle).await;
+            receive_message(stream, &rt_handle).await;
             // if return, means error happened, need to reconnect
             tokio::time::sleep(Duration::from_secs(10)).await; // Wait before attempting to reconnect
         }
@@ -0,0 +1,1285 @@
+// websocket command protocol between agent and control plane
+//
+// Every frame is a JSON envelope:
+//
+//   { "v": 2, "id": "<request id>", "name": "<command or event>", "data": { ... } }
+//
+// `v` is the protocol version. `id` is chosen by the server for each command
+// and echoed by the agent in every frame it sends for that command, so several
+// commands can be in flight at once.
+//
+// Commands are bound to the connection they came in on. When it drops the
+// agent cancels them without sending `cancelled`, the server sends them again
+// after reconnecting if they are still wanted. A deploy sent again skips the
+// stages that were done already.
+//
+// Frames without `v`, or with "v": 1, are the format of agents before the
+// envelope existed: the id may be left out, the agent makes one up then,
+// query takes the bare ip as data, and scan_result carries its machines as a
+// JSON encoded string. Everything else is the same as version 2, the agent
+// answers in the version of the command.
+// Inbound commands:
+//   scan   { "ip": "192.168.1.10", "pwd": "..." }
+//   deploy { "ip": "192.168.1.10", "pwd": "...", "ver": "0.2.3", "addr": "aleo1..." }
+//          "sha256": "..."  optional checksum of the prover tarball. The agent caches
+//                   the tarball and the cuda keyring in ~/.lcd-agent/artifacts and pushes
+//                   them to the hosts, so the hosts don't download them. The driver, cuda
+//                   and jq still come from apt, hosts need internet access. Files copied
+//                   there by hand are used as well, there is no upload command. Without a
+//                   cached copy the hosts download them themselves, unless a sha256 was
+//                   given, a "started" deploy_progress of the stage says so in "detail"
+//          scan and deploy take either `ip` or `targets`:
+//          "targets": { "include": ["10.0.0.0/22", "10.0.8.10-10.0.8.20", "10.0.9.5-9", "10.0.9.77"],
+//                       "exclude": ["10.0.0.1"] }
+//          a bare `ip` scans .1 to .255 of its /24 and deploys to that host only
+//          "concurrency": 16   hosts worked on at once, defaults to 64 for scan and 8 for deploy
+//          "prover": "zkwork"  prover backend to install or read hashrates from, the default
+//          "rollout": { "canary": 1, "wave_size": 16, "max_failure_rate": 0.2,
+//                       "verify_seconds": 1200 }
+//                   deploy only, deploys wave by wave after a canary wave, verify_seconds
+//                   is the deadline of verifying_hashrate, a failed canary host or a wave
+//                   failing above max_failure_rate halts the rollout
+//          scan and deploy take either `pwd` (root password) or `auth`:
+//          "auth": { "user": "root", "port": 22,
+//                    "credential": { "type": "password", "password": "..." }
+//                                | { "type": "key_file", "path": "...", "passphrase": "..." }
+//                                | { "type": "agent", "socket": "/run/ssh-agent.sock" },
+//                    "overrides": [{ "hosts": "192.168.1.0/28", "user": "...",
+//                                    "port": 2222, "credential": { ... } }] }
+//   reboot { "targets": { ... }, "pwd": "...", "concurrency": 16 }
+//          reboot the hosts and wait until they are back up
+//   restart_prover { "targets": { ... }, "pwd": "...", "prover": "zkwork" }
+//          start the prover service, or restart it when it runs, and wait until it runs
+//   update { "targets": { ... }, "pwd": "...", "ver": "0.2.4", "addr": "aleo1..." }
+//          upgrade the prover in place without touching drivers or cuda, `ver` and
+//          `addr` are optional but one is required, what is left out stays as installed,
+//          "sha256" checks the tarball of `ver` like deploy
+//          deploy and update take "dry_run": true to only inspect the targets and
+//          answer with plan_result and plan_done, nothing on the hosts is changed
+//          reboot, restart_prover and update take `ip` or `targets`, `pwd` or `auth`
+//          and `concurrency` like deploy, a bare `ip` is that host only
+//   query  { "ip": "192.168.1.10", "pwd": "...", "prover": "zkwork" }
+//          live status of one host, `pwd` or `auth` like scan
+//   cancel { "request_id": "<id of the scan/deploy to cancel>" }
+//   approve_host_key { "host": "192.168.1.10", "port": 22, "fingerprint": "SHA256:..." }
+//          trust the changed key the host presented last
+//   pin_host_key { "host": "192.168.1.10", "port": 22, "key": "ssh-ed25519 AAAA..." }
+//          trust exactly this key, approvals can't replace a pinned key
+// Outbound events:
+//   scan_result   [MachineInfo, ...]          partial result, sent as machines are collected,
+//                 up to 10 per frame
+//                 one per host that answered on the ssh port, "status" is one of
+//                 collected, auth_failed, host_key_changed, connection_failed, timeout,
+//                 collector_missing, jq_missing, nvidia_smi_failed, invalid_output,
+//                 command_failed, with the cause in "error" unless collected
+//                 "prover_error" says why "prover_info" is empty, e.g. no table in prover.log
+//   scan_done     { "hosts": 255, "machines": 3, "chunks": 1, "unreachable": ["..."],
+//                   "auth_failed": ["..."], "failed": ["..."] }
+//                 unreachable hosts have nothing on the ssh port and get no
+//                 scan_result entry, auth_failed and failed summarize the statuses
+//   query_result  MachineInfo                 status of the queried host, "status" is
+//                 unreachable when nothing answers on the ssh port, "collected_at" is
+//                 the unix time in ms it was read and "prover_age" the seconds since
+//                 the prover last logged a hashrate table
+//   deploy_progress { "ip": "...", "stage": "installing_cuda", "status": "started",
+//                     "timestamp": 1728814977000, "stderr_tail": "..." }
+//                 deploy inspects the host first and skips what is in place already,
+//                 "status": "skipped" comes with the reason in "detail", so a deploy run
+//                 again after a failure resumes at the failed stage. A driver that
+//                 isn't loaded after a reboot fails installing_driver instead of
+//                 rebooting the host again
+//                 rebooting lasts until the host is back, then verifying_driver,
+//                 verifying_service and verifying_hashrate confirm nvidia-smi works, the
+//                 prover service is active and a new hashrate table shows up
+//   deploy_result { "ip": "...", "ok": false, "error": "..." }   one per target, sent
+//                 as soon as the host is done, after its deploy_progress frames
+//   deploy_done   { "hosts": 3, "succeeded": 2, "failed": 1 }
+//                 with a rollout also "waves": [{ "wave": 0, "canary": true, "hosts": 1,
+//                 "succeeded": 1, "failed": 0 }, ...] and the "skipped" hosts if it halted
+//   reboot_result / restart_prover_result
+//                 { "ip": "...", "ok": true, "recovery_ms": 73012 }   one per target, sent
+//                 as soon as the host recovered or failed
+//                 { "ip": "...", "ok": false, "error": "..." }
+//   reboot_done / restart_prover_done
+//                 { "hosts": 3, "succeeded": 2, "failed": 1 }
+//   update_result { "ip": "...", "ok": true, "version": "0.2.4", "address": "aleo1...",
+//                   "uptime": 12 }
+//                 { "ip": "...", "ok": false, "error": "..." }   one per target as it is
+//                 done, version and address are what the restarted prover runs, uptime
+//                 the seconds since it started, always after the update
+//   update_done   { "hosts": 3, "succeeded": 2, "failed": 1 }
+//   plan_result   [HostPlan, ...]             dry run, sent in chunks like scan_result
+//                 { "ip": "...", "status": "collected", "ready": true, "wave": 0,
+//                   "installed": { "driver": "550.54.14", "cuda": "12.6.2-1", "scripts": true,
+//                                  "prover": { "version": "0.2.3", "address": "aleo1..." },
+//                                  "prover_running": true },
+//                   "steps": [{ "stage": "downloading_prover", "action": "upgrade",
+//                               "detail": "0.2.3 -> 0.2.4" }, ...] }
+//                 action is one of install, reinstall, upgrade, change, keep, run, blocked
+//   plan_done     { "hosts": 3, "succeeded": 2, "failed": 1 }   failed hosts aren't ready
+//   cancel_result { "request_id": "...", "found": true }
+//   cancelled     { "completed": [...], "aborted": [...], "not_started": [...] }
+//                 final frame of a cancelled command, sent with its own id
+//   host_key_trusted { "host": "...", "port": 22, "fingerprint": "SHA256:...", "pinned": true }
+//   host_key_changed { "host": "...", "port": 22, "expected": "SHA256:...", "actual": "SHA256:..." }
+//                 sent without id whenever a host presents a new unknown key
+//   error         { "code": "...", "message": "...", "command": "scan" }
+//
+// Any frame that can't be parsed or validated is answered with an `error`
+// event, carrying the request id if one could be read from the frame.
+
+use std::net::Ipv4Addr;
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::collector::{
+    BatchReport, DeployProgress, HostPlan, MachineInfo, RolloutPlan, TargetSpec, WaveDone,
+    CONTROL_CONCURRENCY, DEPLOY_CONCURRENCY, SCAN_CONCURRENCY, UPDATE_CONCURRENCY,
+};
+use crate::error::AgentError;
+use crate::known_hosts::HostKeyChange;
+use crate::prover::{self, ProverBackend, ProverConfig, ProverUpdate, RunningProver};
+use crate::sh::{AuthConfig, DEFAULT_PORT};
+
+pub const PROTOCOL_VERSION: u32 = 2;
+// frames without a version
+pub const LEGACY_VERSION: u32 = 1;
+
+// upper bound of the per command concurrency
+const MAX_CONCURRENCY: usize = 1024;
+
+const COMMAND_NAMES: &[&str] = &[
+    "scan",
+    "deploy",
+    "reboot",
+    "restart_prover",
+    "update",
+    "query",
+    "cancel",
+    "approve_host_key",
+    "pin_host_key",
+];
+
+lazy_static! {
+    // ids of legacy commands sent without one
+    static ref LEGACY_IDS: AtomicU64 = AtomicU64::new(1);
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InboundEnvelope {
+    #[serde(default)]
+    v: Option<u32>,
+    #[serde(default)]
+    id: Option<String>,
+    name: String,
+    #[serde(default)]
+    data: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct OutboundEnvelope<'a> {
+    v: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<&'a str>,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+// a command together with the request id and version it was sent with
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub id: String,
+    pub version: u32,
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "name", content = "data", rename_all = "snake_case")]
+pub enum Command {
+    Scan(ScanRequest),
+    Deploy(DeployRequest),
+    Reboot(RebootRequest),
+    RestartProver(RestartProverRequest),
+    Update(UpdateRequest),
+    Query(QueryRequest),
+    Cancel(CancelRequest),
+    ApproveHostKey(ApproveHostKeyRequest),
+    PinHostKey(PinHostKeyRequest),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScanRequest {
+    #[serde(default)]
+    pub ip: Option<String>,
+    #[serde(default)]
+    pub targets: Option<TargetSpec>,
+    #[serde(default)]
+    pub concurrency: Option<usize>,
+    #[serde(default)]
+    pub prover: Option<String>,
+    #[serde(default)]
+    pub pwd: Option<String>,
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeployRequest {
+    #[serde(default)]
+    pub ip: Option<String>,
+    #[serde(default)]
+    pub targets: Option<TargetSpec>,
+    #[serde(default)]
+    pub concurrency: Option<usize>,
+    #[serde(default)]
+    pub prover: Option<String>,
+    #[serde(default)]
+    pub pwd: Option<String>,
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+    pub ver: String,
+    pub addr: String,
+    #[serde(default)]
+    pub sha256: Option<String>,
+    #[serde(default)]
+    pub rollout: Option<RolloutPlan>,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RebootRequest {
+    #[serde(default)]
+    pub ip: Option<String>,
+    #[serde(default)]
+    pub targets: Option<TargetSpec>,
+    #[serde(default)]
+    pub concurrency: Option<usize>,
+    #[serde(default)]
+    pub pwd: Option<String>,
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RestartProverRequest {
+    #[serde(default)]
+    pub ip: Option<String>,
+    #[serde(default)]
+    pub targets: Option<TargetSpec>,
+    #[serde(default)]
+    pub concurrency: Option<usize>,
+    #[serde(default)]
+    pub prover: Option<String>,
+    #[serde(default)]
+    pub pwd: Option<String>,
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateRequest {
+    #[serde(default)]
+    pub ip: Option<String>,
+    #[serde(default)]
+    pub targets: Option<TargetSpec>,
+    #[serde(default)]
+    pub concurrency: Option<usize>,
+    #[serde(default)]
+    pub prover: Option<String>,
+    #[serde(default)]
+    pub pwd: Option<String>,
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+    #[serde(default)]
+    pub ver: Option<String>,
+    #[serde(default)]
+    pub addr: Option<String>,
+    #[serde(default)]
+    pub sha256: Option<String>,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QueryRequest {
+    pub ip: String,
+    #[serde(default)]
+    pub prover: Option<String>,
+    #[serde(default)]
+    pub pwd: Option<String>,
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CancelRequest {
+    pub request_id: String,
+}
+
+fn default_port() -> u16 {
+    DEFAULT_PORT
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApproveHostKeyRequest {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub fingerprint: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PinHostKeyRequest {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub key: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "name", content = "data", rename_all = "snake_case")]
+pub enum Event {
+    ScanResult(Vec<MachineInfo>),
+    ScanDone(ScanDone),
+    QueryResult(Box<MachineInfo>),
+    DeployProgress(DeployProgress),
+    DeployResult(DeployResult),
+    DeployDone(DeployDone),
+    RebootResult(ControlResult),
+    RebootDone(BatchDone),
+    RestartProverResult(ControlResult),
+    RestartProverDone(BatchDone),
+    UpdateResult(UpdateResult),
+    UpdateDone(BatchDone),
+    PlanResult(Vec<HostPlan>),
+    PlanDone(BatchDone),
+    CancelResult(CancelResult),
+    Cancelled(BatchReport),
+    HostKeyTrusted(HostKeyTrusted),
+    HostKeyChanged(HostKeyChange),
+    Error(ErrorReply),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScanDone {
+    // number of addresses scanned
+    pub hosts: usize,
+    // number of machines reported in scan_result frames
+    pub machines: usize,
+    // number of scan_result frames sent
+    pub chunks: usize,
+    // nothing listening on the ssh port
+    pub unreachable: Vec<String>,
+    // credentials rejected
+    pub auth_failed: Vec<String>,
+    // logged in, but collecting failed
+    pub failed: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeployResult {
+    pub ip: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// summary after the per host results of a batch command
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BatchDone {
+    pub hosts: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DeployDone {
+    #[serde(flatten)]
+    pub summary: BatchDone,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub waves: Vec<WaveDone>,
+    // hosts a halted rollout didn't deploy to, no deploy_result for them
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub skipped: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ControlResult {
+    pub ip: String,
+    pub ok: bool,
+    // from issuing the command until the host or service was back
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recovery_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UpdateResult {
+    pub ip: String,
+    pub ok: bool,
+    // what the restarted prover runs
+    #[serde(flatten)]
+    pub running: Option<RunningProver>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CancelResult {
+    pub request_id: String,
+    // false when no command with that id is running
+    pub found: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HostKeyTrusted {
+    pub host: String,
+    pub port: u16,
+    pub fingerprint: String,
+    pub pinned: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    // frame is not a valid json envelope
+    InvalidMessage,
+    // envelope version is not supported by this agent
+    UnsupportedVersion,
+    // `name` doesn't match any known command
+    UnknownCommand,
+    // `data` doesn't match the command schema or fails validation
+    InvalidData,
+    // a command with the same id is still running
+    DuplicateRequest,
+    // command was accepted but failed while executing
+    CommandFailed,
+}
+
+// a rejected inbound frame, `id` is set when the frame carried one
+#[derive(Debug, Clone)]
+pub struct Rejected {
+    pub id: Option<String>,
+    // version to answer in
+    pub version: u32,
+    pub reply: ErrorReply,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorReply {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+}
+
+impl ErrorReply {
+    pub fn new(code: ErrorCode, message: impl Into<String>, command: Option<&str>) -> Self {
+        ErrorReply {
+            code,
+            message: message.into(),
+            command: command.map(|c| c.to_owned()),
+        }
+    }
+}
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Scan(_) => "scan",
+            Command::Deploy(_) => "deploy",
+            Command::Reboot(_) => "reboot",
+            Command::RestartProver(_) => "restart_prover",
+            Command::Update(_) => "update",
+            Command::Query(_) => "query",
+            Command::Cancel(_) => "cancel",
+            Command::ApproveHostKey(_) => "approve_host_key",
+            Command::PinHostKey(_) => "pin_host_key",
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), AgentError> {
+        match self {
+            Command::Scan(req) => {
+                check_targets(&req.ip, &req.targets)?;
+                check_concurrency(req.concurrency)?;
+                check_prover(&req.prover)?;
+                check_auth(&req.pwd, &req.auth)
+            }
+            Command::Deploy(req) => {
+                check_targets(&req.ip, &req.targets)?;
+                check_concurrency(req.concurrency)?;
+                check_prover(&req.prover)?;
+                check_auth(&req.pwd, &req.auth)?;
+                check_version(&req.ver)?;
+                check_address(&req.addr)?;
+                check_sha256(&req.sha256)?;
+                match &req.rollout {
+                    Some(plan) => plan.validate(),
+                    None => Ok(()),
+                }
+            }
+            Command::Reboot(req) => {
+                check_targets(&req.ip, &req.targets)?;
+                check_concurrency(req.concurrency)?;
+                check_auth(&req.pwd, &req.auth)
+            }
+            Command::RestartProver(req) => {
+                check_targets(&req.ip, &req.targets)?;
+                check_concurrency(req.concurrency)?;
+                check_prover(&req.prover)?;
+                check_auth(&req.pwd, &req.auth)
+            }
+            Command::Update(req) => {
+                check_targets(&req.ip, &req.targets)?;
+                check_concurrency(req.concurrency)?;
+                check_prover(&req.prover)?;
+                check_auth(&req.pwd, &req.auth)?;
+                if req.ver.is_none() && req.addr.is_none() {
+                    return Err(AgentError::ProtocolError(
+                        "update needs ver, addr or both".to_owned(),
+                    ));
+                }
+                if let Some(ver) = &req.ver {
+                    check_version(ver)?;
+                }
+                if let Some(addr) = &req.addr {
+                    check_address(addr)?;
+                }
+                if req.sha256.is_some() && req.ver.is_none() {
+                    return Err(AgentError::ProtocolError(
+                        "sha256 is the checksum of ver, it needs ver".to_owned(),
+                    ));
+                }
+                check_sha256(&req.sha256)
+            }
+            Command::Query(req) => {
+                check_ip(&req.ip)?;
+                check_prover(&req.prover)?;
+                check_auth(&req.pwd, &req.auth)
+            }
+            Command::Cancel(req) => check_not_empty("request_id", &req.request_id),
+            Command::ApproveHostKey(req) => {
+                check_ip(&req.host)?;
+                check_not_empty("fingerprint", &req.fingerprint)
+            }
+            Command::PinHostKey(req) => {
+                check_ip(&req.host)?;
+                check_not_empty("key", &req.key)
+            }
+        }
+    }
+}
+
+impl ScanRequest {
+    pub fn auth(&self) -> AuthConfig {
+        resolve_auth(&self.pwd, &self.auth)
+    }
+
+    pub fn targets(&self) -> TargetSpec {
+        match (&self.targets, &self.ip) {
+            (Some(targets), _) => targets.clone(),
+            (None, Some(ip)) => ip.parse().map(TargetSpec::subnet).unwrap_or_default(),
+            (None, None) => TargetSpec::default(),
+        }
+    }
+
+    pub fn concurrency(&self) -> usize {
+        self.concurrency.unwrap_or(SCAN_CONCURRENCY)
+    }
+
+    pub fn prover(&self) -> Arc<dyn ProverBackend> {
+        resolve_prover(&self.prover)
+    }
+}
+
+impl DeployRequest {
+    pub fn auth(&self) -> AuthConfig {
+        resolve_auth(&self.pwd, &self.auth)
+    }
+
+    pub fn targets(&self) -> TargetSpec {
+        resolve_hosts(&self.ip, &self.targets)
+    }
+
+    pub fn concurrency(&self) -> usize {
+        self.concurrency.unwrap_or(DEPLOY_CONCURRENCY)
+    }
+
+    pub fn prover(&self) -> Arc<dyn ProverBackend> {
+        resolve_prover(&self.prover)
+    }
+
+    pub fn prover_config(&self) -> ProverConfig {
+        ProverConfig {
+            version: self.ver.clone(),
+            address: self.addr.clone(),
+            sha256: self.sha256.as_ref().map(|sha256| sha256.to_lowercase()),
+        }
+    }
+}
+
+impl RebootRequest {
+    pub fn auth(&self) -> AuthConfig {
+        resolve_auth(&self.pwd, &self.auth)
+    }
+
+    pub fn targets(&self) -> TargetSpec {
+        resolve_hosts(&self.ip, &self.targets)
+    }
+
+    pub fn concurrency(&self) -> usize {
+        self.concurrency.unwrap_or(CONTROL_CONCURRENCY)
+    }
+}
+
+impl RestartProverRequest {
+    pub fn auth(&self) -> AuthConfig {
+        resolve_auth(&self.pwd, &self.auth)
+    }
+
+    pub fn targets(&self) -> TargetSpec {
+        resolve_hosts(&self.ip, &self.targets)
+    }
+
+    pub fn concurrency(&self) -> usize {
+        self.concurrency.unwrap_or(CONTROL_CONCURRENCY)
+    }
+
+    pub fn prover(&self) -> Arc<dyn ProverBackend> {
+        resolve_prover(&self.prover)
+    }
+}
+
+impl UpdateRequest {
+    pub fn auth(&self) -> AuthConfig {
+        resolve_auth(&self.pwd, &self.auth)
+    }
+
+    pub fn targets(&self) -> TargetSpec {
+        resolve_hosts(&self.ip, &self.targets)
+    }
+
+    pub fn concurrency(&self) -> usize {
+        self.concurrency.unwrap_or(UPDATE_CONCURRENCY)
+    }
+
+    pub fn prover(&self) -> Arc<dyn ProverBackend> {
+        resolve_prover(&self.prover)
+    }
+
+    pub fn prover_update(&self) -> ProverUpdate {
+        ProverUpdate {
+            version: self.ver.clone(),
+            address: self.addr.clone(),
+            sha256: self.sha256.as_ref().map(|sha256| sha256.to_lowercase()),
+        }
+    }
+}
+
+impl QueryRequest {
+    pub fn auth(&self) -> AuthConfig {
+        resolve_auth(&self.pwd, &self.auth)
+    }
+
+    pub fn prover(&self) -> Arc<dyn ProverBackend> {
+        resolve_prover(&self.prover)
+    }
+}
+
+// a bare ip is that host alone
+fn resolve_hosts(ip: &Option<String>, targets: &Option<TargetSpec>) -> TargetSpec {
+    match (targets, ip) {
+        (Some(targets), _) => targets.clone(),
+        (None, Some(ip)) => TargetSpec::new(&[ip]),
+        (None, None) => TargetSpec::default(),
+    }
+}
+
+// names are checked by validate, unknown ones fall back to the default
+fn resolve_prover(name: &Option<String>) -> Arc<dyn ProverBackend> {
+    name.as_deref()
+        .and_then(prover::backend)
+        .unwrap_or_else(prover::default_backend)
+}
+
+// a bare pwd means root on port 22, as before auth existed
+fn resolve_auth(pwd: &Option<String>, auth: &Option<AuthConfig>) -> AuthConfig {
+    match (auth, pwd) {
+        (Some(auth), _) => auth.clone(),
+        (None, Some(pwd)) => AuthConfig::password(pwd),
+        (None, None) => AuthConfig::password(""),
+    }
+}
+
+impl Event {
+    // serialize the event into an envelope echoing the request id and version
+    pub fn to_message(&self, id: Option<&str>, version: u32) -> Result<String, AgentError> {
+        if let (LEGACY_VERSION, Event::ScanResult(machines)) = (version, self) {
+            return Ok(serde_json::json!({
+                "v": version,
+                "id": id,
+                "name": "scan_result",
+                "data": serde_json::to_string(machines)?,
+            })
+            .to_string());
+        }
+        Ok(serde_json::to_string(&OutboundEnvelope {
+            v: version,
+            id,
+            event: self,
+        })?)
+    }
+}
+
+// parse and validate an inbound text frame
+pub fn parse_command(text: &str) -> Result<Request, Rejected> {
+    let (id, version) = peek_id(text);
+    let envelope: InboundEnvelope = serde_json::from_str(text).map_err(|e| Rejected {
+        id,
+        version,
+        reply: ErrorReply::new(ErrorCode::InvalidMessage, e.to_string(), None),
+    })?;
+
+    let version = envelope.v.unwrap_or(LEGACY_VERSION);
+    let name = envelope.name.as_str();
+    let id = match envelope.id {
+        Some(id) => id,
+        None if version == LEGACY_VERSION => {
+            format!("legacy-{}", LEGACY_IDS.fetch_add(1, Ordering::Relaxed))
+        }
+        None => String::new(),
+    };
+    let reject = |code, message: String| Rejected {
+        id: Some(id.clone()).filter(|id| !id.is_empty()),
+        version: if version == LEGACY_VERSION {
+            LEGACY_VERSION
+        } else {
+            PROTOCOL_VERSION
+        },
+        reply: ErrorReply::new(code, message, Some(name)),
+    };
+
+    if version != PROTOCOL_VERSION && version != LEGACY_VERSION {
+        return Err(reject(
+            ErrorCode::UnsupportedVersion,
+            format!(
+                "protocol version {} is not supported, expected {}",
+                version, PROTOCOL_VERSION
+            ),
+        ));
+    }
+
+    if id.trim().is_empty() {
+        return Err(reject(
+            ErrorCode::InvalidMessage,
+            "id is missing or empty".to_owned(),
+        ));
+    }
+
+    if !COMMAND_NAMES.contains(&name) {
+        return Err(reject(
+            ErrorCode::UnknownCommand,
+            format!("unknown command: {}", name),
+        ));
+    }
+
+    let data = match envelope.data {
+        // legacy query
+        Value::String(ip) if version == LEGACY_VERSION && name == "query" => {
+            serde_json::json!({ "ip": ip })
+        }
+        data => data,
+    };
+    let command: Command = serde_json::from_value(serde_json::json!({
+        "name": name,
+        "data": data,
+    }))
+    .map_err(|e| reject(ErrorCode::InvalidData, e.to_string()))?;
+
+    command
+        .validate()
+        .map_err(|e| reject(ErrorCode::InvalidData, e.to_string()))?;
+
+    Ok(Request {
+        id,
+        version,
+        command,
+    })
+}
+
+// best effort read of the request id and version from a frame that failed to parse
+fn peek_id(text: &str) -> (Option<String>, u32) {
+    let value: Value = serde_json::from_str(text).unwrap_or_default();
+    let id = value["id"].as_str().map(|id| id.to_owned());
+    match value["v"].as_u64() {
+        None | Some(1) => (id, LEGACY_VERSION),
+        _ => (id, PROTOCOL_VERSION),
+    }
+}
+
+fn check_not_empty(field: &str, value: &str) -> Result<(), AgentError> {
+    if value.trim().is_empty() {
+        return Err(AgentError::ProtocolError(format!("{} is empty", field)));
+    }
+    Ok(())
+}
+
+// exactly one of pwd and auth
+fn check_auth(pwd: &Option<String>, auth: &Option<AuthConfig>) -> Result<(), AgentError> {
+    match (pwd, auth) {
+        (Some(pwd), None) => check_not_empty("pwd", pwd),
+        (None, Some(auth)) => auth.validate(),
+        (Some(_), Some(_)) => Err(AgentError::ProtocolError(
+            "pwd and auth are exclusive".to_owned(),
+        )),
+        (None, None) => Err(AgentError::ProtocolError(
+            "pwd or auth is required".to_owned(),
+        )),
+    }
+}
+
+// exactly one of ip and targets
+fn check_targets(ip: &Option<String>, targets: &Option<TargetSpec>) -> Result<(), AgentError> {
+    match (ip, targets) {
+        (Some(ip), None) => check_ip(ip),
+        (None, Some(targets)) => targets.validate(),
+        (Some(_), Some(_)) => Err(AgentError::ProtocolError(
+            "ip and targets are exclusive".to_owned(),
+        )),
+        (None, None) => Err(AgentError::ProtocolError(
+            "ip or targets is required".to_owned(),
+        )),
+    }
+}
+
+fn check_prover(name: &Option<String>) -> Result<(), AgentError> {
+    match name {
+        Some(name) if prover::backend(name).is_none() => Err(AgentError::ProtocolError(format!(
+            "unknown prover: {:?}",
+            name
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn check_concurrency(concurrency: Option<usize>) -> Result<(), AgentError> {
+    match concurrency {
+        Some(n) if n == 0 || n > MAX_CONCURRENCY => Err(AgentError::ProtocolError(format!(
+            "concurrency must be between 1 and {}",
+            MAX_CONCURRENCY
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn check_ip(ip: &str) -> Result<(), AgentError> {
+    ip.parse::<Ipv4Addr>()
+        .map(|_| ())
+        .map_err(|_| AgentError::ProtocolError(format!("invalid ip: {:?}", ip)))
+}
+
+// prover versions look like 0.2.3
+fn check_version(ver: &str) -> Result<(), AgentError> {
+    let valid = !ver.is_empty()
+        && ver
+            .split('.')
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()));
+    if !valid {
+        return Err(AgentError::ProtocolError(format!(
+            "invalid version: {:?}",
+            ver
+        )));
+    }
+    Ok(())
+}
+
+fn check_address(addr: &str) -> Result<(), AgentError> {
+    let valid = addr.starts_with("aleo1") && addr.chars().all(|c| c.is_ascii_alphanumeric());
+    if !valid {
+        return Err(AgentError::ProtocolError(format!(
+            "invalid receive address: {:?}",
+            addr
+        )));
+    }
+    Ok(())
+}
+
+// hex sha256, of any case
+fn check_sha256(sha256: &Option<String>) -> Result<(), AgentError> {
+    match sha256 {
+        Some(sha256) if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) => {
+            Err(AgentError::ProtocolError(format!(
+                "invalid sha256: {:?}",
+                sha256
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+// test
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_scan() {
+        let req = parse_command(
+            r#"{"v":2,"id":"r1","name":"scan","data":{"ip":"192.168.1.2","pwd":"x"}}"#,
+        )
+        .unwrap();
+        assert_eq!(req.id, "r1");
+        assert_eq!(
+            req.command,
+            Command::Scan(ScanRequest {
+                ip: Some("192.168.1.2".to_owned()),
+                targets: None,
+                concurrency: None,
+                prover: None,
+                pwd: Some("x".to_owned()),
+                auth: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_auth() {
+        let req = parse_command(
+            r#"{"v":2,"id":"r1","name":"scan","data":{"ip":"192.168.1.2",
+                "auth":{"user":"ubuntu","credential":{"type":"agent"}}}}"#,
+        )
+        .unwrap();
+        let Command::Scan(scan) = req.command else {
+            panic!("not a scan");
+        };
+        let target = scan.auth().target("192.168.1.9");
+        assert_eq!(target.user, "ubuntu");
+        assert_eq!(target.port, 22);
+
+        // pwd and auth are exclusive
+        let err = parse_command(
+            r#"{"v":2,"id":"r1","name":"scan","data":{"ip":"192.168.1.2","pwd":"x",
+                "auth":{"credential":{"type":"agent"}}}}"#,
+        )
+        .unwrap_err();
+        assert_eq!(err.reply.code, ErrorCode::InvalidData);
+    }
+
+    #[test]
+    fn test_parse_targets() {
+        let req = parse_command(
+            r#"{"v":2,"id":"r1","name":"scan","data":{"pwd":"x",
+                "targets":{"include":["10.0.0.0/30","10.0.1.5-6"],"exclude":["10.0.1.6"]}}}"#,
+        )
+        .unwrap();
+        let Command::Scan(scan) = req.command else {
+            panic!("not a scan");
+        };
+        assert_eq!(
+            scan.targets().ips().unwrap(),
+            vec!["10.0.0.1", "10.0.0.2", "10.0.1.5"]
+        );
+
+        // a bare ip still means its /24 for scan and the host itself for deploy
+        let req = parse_command(
+            r#"{"v":2,"id":"r2","name":"deploy","data":{"ip":"10.0.0.9","pwd":"x","ver":"0.2.3","addr":"aleo1abc"}}"#,
+        )
+        .unwrap();
+        let Command::Deploy(deploy) = req.command else {
+            panic!("not a deploy");
+        };
+        assert_eq!(deploy.targets().ips().unwrap(), vec!["10.0.0.9"]);
+        assert_eq!(deploy.concurrency(), DEPLOY_CONCURRENCY);
+        assert_eq!(deploy.prover().name(), prover::DEFAULT_BACKEND);
+        assert_eq!(deploy.prover_config().version, "0.2.3");
+        assert_eq!(deploy.prover_config().sha256, None);
+        assert_eq!(deploy.rollout, None);
+
+        let sha256 = "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD";
+        let req = parse_command(&format!(
+            r#"{{"v":2,"id":"r3","name":"deploy","data":{{"ip":"10.0.0.9","pwd":"x","ver":"0.2.3","addr":"aleo1abc","sha256":"{}"}}}}"#,
+            sha256
+        ))
+        .unwrap();
+        let Command::Deploy(deploy) = req.command else {
+            panic!("not a deploy");
+        };
+        assert_eq!(deploy.prover_config().sha256, Some(sha256.to_lowercase()));
+        let err = parse_command(
+            r#"{"v":2,"id":"r3","name":"deploy","data":{"ip":"10.0.0.9","pwd":"x","ver":"0.2.3","addr":"aleo1abc","sha256":"abc"}}"#,
+        )
+        .unwrap_err();
+        assert!(err.reply.message.contains("sha256"));
+
+        let req = parse_command(
+            r#"{"v":2,"id":"r4","name":"deploy","data":{"targets":{"include":["10.0.0.0/24"]},"pwd":"x",
+                "ver":"0.2.3","addr":"aleo1abc","rollout":{"canary":2,"wave_size":10}}}"#,
+        )
+        .unwrap();
+        let Command::Deploy(deploy) = req.command else {
+            panic!("not a deploy");
+        };
+        let rollout = deploy.rollout.clone().unwrap();
+        assert_eq!(rollout.canary, 2);
+        assert_eq!(rollout.waves(&deploy.targets().ips().unwrap()).len(), 27);
+
+        let err = parse_command(
+            r#"{"v":2,"id":"r5","name":"deploy","data":{"ip":"10.0.0.9","pwd":"x","ver":"0.2.3",
+                "addr":"aleo1abc","rollout":{"wave_size":0}}}"#,
+        )
+        .unwrap_err();
+        assert!(
+            err.reply.message.contains("wave_size"),
+            "{}",
+            err.reply.message
+        );
+
+        for data in [
+            r#"{"ip":"10.0.0.1","targets":{"include":["10.0.0.1"]},"pwd":"x"}"#,
+            r#"{"targets":{"include":["10.0.0.0/33"]},"pwd":"x"}"#,
+            r#"{"targets":{"include":["10.0.0.1"],"exclude":["10.0.0.1"]},"pwd":"x"}"#,
+            r#"{"ip":"10.0.0.1","pwd":"x","concurrency":0}"#,
+            r#"{"ip":"10.0.0.1","pwd":"x","prover":"bzminer"}"#,
+        ] {
+            let text = format!(r#"{{"v":2,"id":"r3","name":"scan","data":{}}}"#, data);
+            let err = parse_command(&text).unwrap_err();
+            assert_eq!(err.reply.code, ErrorCode::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_parse_control() {
+        let req = parse_command(
+            r#"{"v":2,"id":"r1","name":"reboot","data":{"targets":{"include":["10.0.0.1-3"]},"pwd":"x"}}"#,
+        )
+        .unwrap();
+        let Command::Reboot(reboot) = req.command else {
+            panic!("not a reboot");
+        };
+        assert_eq!(reboot.targets().ips().unwrap().len(), 3);
+        assert_eq!(reboot.concurrency(), CONTROL_CONCURRENCY);
+
+        let req = parse_command(
+            r#"{"v":2,"id":"r2","name":"restart_prover","data":{"ip":"10.0.0.7","pwd":"x","prover":"zkwork"}}"#,
+        )
+        .unwrap();
+        let Command::RestartProver(restart) = req.command else {
+            panic!("not a restart_prover");
+        };
+        assert_eq!(restart.targets().ips().unwrap(), vec!["10.0.0.7"]);
+
+        let err =
+            parse_command(r#"{"v":2,"id":"r3","name":"reboot","data":{"pwd":"x"}}"#).unwrap_err();
+        assert_eq!(err.reply.code, ErrorCode::InvalidData);
+    }
+
+    #[test]
+    fn test_parse_update() {
+        let req = parse_command(
+            r#"{"v":2,"id":"r1","name":"update","data":{"targets":{"include":["10.0.0.1-4"]},"pwd":"x","ver":"0.2.4"}}"#,
+        )
+        .unwrap();
+        let Command::Update(update) = req.command else {
+            panic!("not an update");
+        };
+        assert_eq!(update.concurrency(), UPDATE_CONCURRENCY);
+        assert!(!update.dry_run);
+        assert_eq!(
+            update.prover_update(),
+            ProverUpdate {
+                version: Some("0.2.4".to_owned()),
+                address: None,
+                sha256: None,
+            }
+        );
+
+        let req = parse_command(
+            r#"{"v":2,"id":"r4","name":"update","data":{"ip":"10.0.0.1","pwd":"x","addr":"aleo1abc","dry_run":true}}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            req.command,
+            Command::Update(UpdateRequest { dry_run: true, .. })
+        ));
+
+        // nothing to update
+        let err = parse_command(
+            r#"{"v":2,"id":"r2","name":"update","data":{"ip":"10.0.0.1","pwd":"x"}}"#,
+        )
+        .unwrap_err();
+        assert_eq!(err.reply.code, ErrorCode::InvalidData);
+
+        let err = parse_command(
+            r#"{"v":2,"id":"r3","name":"update","data":{"ip":"10.0.0.1","pwd":"x","addr":"nope"}}"#,
+        )
+        .unwrap_err();
+        assert!(err.reply.message.contains("receive address"));
+
+        // the checksum belongs to the tarball of ver
+        let err = parse_command(&format!(
+            r#"{{"v":2,"id":"r5","name":"update","data":{{"ip":"10.0.0.1","pwd":"x","addr":"aleo1abc","sha256":"{}"}}}}"#,
+            "0".repeat(64)
+        ))
+        .unwrap_err();
+        assert!(err.reply.message.contains("needs ver"));
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let req =
+            parse_command(r#"{"v":2,"id":"r1","name":"query","data":{"ip":"10.0.0.1","pwd":"x"}}"#)
+                .unwrap();
+        assert_eq!(req.command.name(), "query");
+        assert_eq!(req.version, PROTOCOL_VERSION);
+
+        // query logs in, so it needs credentials too
+        let err = parse_command(r#"{"v":2,"id":"r2","name":"query","data":{"ip":"10.0.0.1"}}"#)
+            .unwrap_err();
+        assert_eq!(err.reply.code, ErrorCode::InvalidData);
+    }
+
+    #[test]
+    fn test_parse_legacy() {
+        // no version and no id, as sent before the envelope existed
+        let req =
+            parse_command(r#"{"name":"scan","data":{"ip":"192.168.1.2","pwd":"x"}}"#).unwrap();
+        assert_eq!(req.version, LEGACY_VERSION);
+        assert!(req.id.starts_with("legacy-"));
+        let other =
+            parse_command(r#"{"name":"scan","data":{"ip":"192.168.1.2","pwd":"x"}}"#).unwrap();
+        assert_ne!(req.id, other.id);
+
+        let err = parse_command(r#"{"v":1,"name":"query","data":"10.0.0.1"}"#).unwrap_err();
+        // the legacy query had no credentials, the ip is still read from the string
+        assert_eq!(err.reply.code, ErrorCode::InvalidData);
+        assert!(err.reply.message.contains("pwd"), "{}", err.reply.message);
+        assert_eq!(err.version, LEGACY_VERSION);
+
+        // the new shapes are understood in version 1 as well
+        let req = parse_command(r#"{"id":"r1","name":"query","data":{"ip":"10.0.0.1","pwd":"x"}}"#)
+            .unwrap();
+        assert_eq!(req.id, "r1");
+        assert_eq!(req.version, LEGACY_VERSION);
+
+        // a bare ip is legacy only
+        let err =
+            parse_command(r#"{"v":2,"id":"r2","name":"query","data":"10.0.0.1"}"#).unwrap_err();
+        assert_eq!(err.reply.code, ErrorCode::InvalidData);
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_input() {
+        let err = parse_command("not json").unwrap_err();
+        assert_eq!(err.reply.code, ErrorCode::InvalidMessage);
+        assert_eq!(err.id, None);
+
+        // missing id
+        let err = parse_command(r#"{"v":2,"name":"query","data":{"ip":"10.0.0.1"}}"#).unwrap_err();
+        assert_eq!(err.reply.code, ErrorCode::InvalidMessage);
+        assert_eq!(err.id, None);
+
+        let err = parse_command(r#"{"v":3,"id":"r2","name":"scan","data":{}}"#).unwrap_err();
+        assert_eq!(err.reply.code, ErrorCode::UnsupportedVersion);
+        assert_eq!(err.id.as_deref(), Some("r2"));
+
+        let err = parse_command(r#"{"v":2,"id":"r3","name":"format","data":{}}"#).unwrap_err();
+        assert_eq!(err.reply.code, ErrorCode::UnknownCommand);
+
+        // missing pwd
+        let err = parse_command(r#"{"v":2,"id":"r4","name":"scan","data":{"ip":"10.0.0.1"}}"#)
+            .unwrap_err();
+        assert_eq!(err.reply.code, ErrorCode::InvalidData);
+        assert_eq!(err.reply.command.as_deref(), Some("scan"));
+        assert_eq!(err.id.as_deref(), Some("r4"));
+
+        // unknown field
+        let err = parse_command(
+            r#"{"v":2,"id":"r5","name":"query","data":{"ip":"10.0.0.1","pwd":"x","x":1}}"#,
+        )
+        .unwrap_err();
+        assert_eq!(err.reply.code, ErrorCode::InvalidData);
+
+        let err = parse_command(
+            r#"{"v":2,"id":"r6","name":"deploy","data":{"ip":"10.0.0.1","pwd":"x","ver":"v1","addr":"aleo1abc"}}"#,
+        )
+        .unwrap_err();
+        assert_eq!(err.reply.code, ErrorCode::InvalidData);
+    }
+
+    #[test]
+    fn test_event_message() {
+        let event = Event::Error(ErrorReply::new(ErrorCode::UnknownCommand, "x", Some("y")));
+        let value: Value =
+            serde_json::from_str(&event.to_message(Some("r1"), PROTOCOL_VERSION).unwrap()).unwrap();
+        assert_eq!(value["v"], 2);
+        assert_eq!(value["id"], "r1");
+        assert_eq!(value["name"], "error");
+        assert_eq!(value["data"]["code"], "unknown_command");
+        assert_eq!(value["data"]["command"], "y");
+
+        let event = Event::ScanDone(ScanDone {
+            hosts: 255,
+            machines: 0,
+            chunks: 0,
+            unreachable: vec!["10.0.0.1".to_owned()],
+            auth_failed: vec![],
+            failed: vec![],
+        });
+        let value: Value =
+            serde_json::from_str(&event.to_message(None, PROTOCOL_VERSION).unwrap()).unwrap();
+        assert!(value.get("id").is_none());
+        assert_eq!(value["data"]["hosts"], 255);
+        assert_eq!(value["data"]["unreachable"][0], "10.0.0.1");
+
+        let event = Event::UpdateResult(UpdateResult {
+            ip: "10.0.0.1".to_owned(),
+            ok: true,
+            running: Some(RunningProver {
+                version: Some("0.2.4".to_owned()),
+                address: None,
+                uptime: Some(12),
+            }),
+            error: None,
+        });
+        let value: Value =
+            serde_json::from_str(&event.to_message(None, PROTOCOL_VERSION).unwrap()).unwrap();
+        assert_eq!(value["name"], "update_result");
+        assert_eq!(value["data"]["version"], "0.2.4");
+        assert!(value["data"].get("address").is_none());
+        assert!(value["data"].get("error").is_none());
+    }
+
+    #[test]
+    fn test_legacy_scan_result() {
+        let event = Event::ScanResult(vec![MachineInfo {
+            ip: "10.0.0.1".to_owned(),
+            ..Default::default()
+        }]);
+        let value: Value =
+            serde_json::from_str(&event.to_message(Some("r1"), PROTOCOL_VERSION).unwrap()).unwrap();
+        assert_eq!(value["data"][0]["ip"], "10.0.0.1");
+
+        let value: Value =
+            serde_json::from_str(&event.to_message(Some("r1"), LEGACY_VERSION).unwrap()).unwrap();
+        assert_eq!(value["v"], 1);
+        let machines: Value = serde_json::from_str(value["data"].as_str().unwrap()).unwrap();
+        assert_eq!(machines[0]["ip"], "10.0.0.1");
+    }
+}
@@ -0,0 +1,176 @@
+// prover software the agent installs, controls and monitors on gpu hosts
+//
+// Deploy, scan and the prover commands only talk to ProverBackend. Supporting
+// another prover or miner means implementing the trait and listing it in
+// `backend`, host setup such as drivers and cuda stays in the deploy stages.
+
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::artifacts::Artifact;
+use crate::collector::{AsyncOpType, ProverInfo};
+use crate::error::AgentError;
+use crate::sh::{CommandOutput, SshTarget};
+
+mod zkwork;
+
+pub use zkwork::ZkWork;
+
+pub const DEFAULT_BACKEND: &str = "zkwork";
+
+// what to install, as sent with the deploy command
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProverConfig {
+    pub version: String,
+    // reward address
+    pub address: String,
+    // expected checksum of the release tarball, see artifacts
+    pub sha256: Option<String>,
+}
+
+// what the update command changes, None keeps what is installed
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProverUpdate {
+    pub version: Option<String>,
+    pub address: Option<String>,
+    // expected checksum of the release tarball of version
+    pub sha256: Option<String>,
+}
+
+impl ProverUpdate {
+    // true when the running prover has every value the update asked for
+    pub fn applied(&self, running: &RunningProver) -> bool {
+        let matches =
+            |wanted: &Option<String>, actual: &Option<String>| wanted.is_none() || wanted == actual;
+        matches(&self.version, &running.version) && matches(&self.address, &running.address)
+    }
+}
+
+// version installed and address the prover process was started with,
+// None when the host doesn't tell
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RunningProver {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    // seconds the prover process has been running
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uptime: Option<u64>,
+}
+
+pub trait ProverBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // release tarball of the version, the agent caches it and pushes it to the hosts
+    fn artifact(&self, version: &str) -> Artifact;
+
+    // download and unpack the prover
+    fn install(
+        &self,
+        target: &SshTarget,
+        config: &ProverConfig,
+        timeout_seconds: u64,
+    ) -> AsyncOpType<CommandOutput>;
+
+    // write start scripts and register the service so it runs on boot
+    fn configure(
+        &self,
+        target: &SshTarget,
+        config: &ProverConfig,
+        timeout_seconds: u64,
+    ) -> AsyncOpType<CommandOutput>;
+
+    // replace the version and/or address in place, drivers and cuda are left
+    // alone, so is the service, update_ip stops and starts it around the update
+    fn update(
+        &self,
+        target: &SshTarget,
+        update: &ProverUpdate,
+        timeout_seconds: u64,
+    ) -> AsyncOpType<CommandOutput>;
+
+    fn start(&self, target: &SshTarget, timeout_seconds: u64) -> AsyncOpType<()>;
+
+    // a prover that isn't installed counts as stopped
+    fn stop(&self, target: &SshTarget, timeout_seconds: u64) -> AsyncOpType<()>;
+
+    fn restart(&self, target: &SshTarget, timeout_seconds: u64) -> AsyncOpType<()>;
+
+    // true when the prover service is up
+    fn is_running(&self, target: &SshTarget, timeout_seconds: u64) -> AsyncOpType<bool>;
+
+    // what the prover on the host currently runs
+    fn running(&self, target: &SshTarget, timeout_seconds: u64) -> AsyncOpType<RunningProver>;
+
+    // receive address the service is set up with, read from its config, so also
+    // while the prover is stopped. None before configure ran
+    fn configured_address(
+        &self,
+        target: &SshTarget,
+        timeout_seconds: u64,
+    ) -> AsyncOpType<Option<String>>;
+
+    // current hashrates read from the host
+    fn collect_stats(
+        &self,
+        target: &SshTarget,
+        timeout_seconds: u64,
+    ) -> AsyncOpType<Vec<ProverInfo>>;
+
+    // hashrates from the text of the prover log
+    fn parse_log(&self, text: &str) -> Result<Vec<ProverInfo>, AgentError>;
+}
+
+// backend by the name used in commands
+pub fn backend(name: &str) -> Option<Arc<dyn ProverBackend>> {
+    match name {
+        "zkwork" => Some(Arc::new(ZkWork)),
+        _ => None,
+    }
+}
+
+pub fn default_backend() -> Arc<dyn ProverBackend> {
+    backend(DEFAULT_BACKEND).unwrap()
+}
+
+// test
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backend() {
+        assert_eq!(default_backend().name(), DEFAULT_BACKEND);
+        assert!(backend("bzminer").is_none());
+
+        let rows = backend("zkwork")
+            .unwrap()
+            .parse_log(
+                "| 2024-10-13T10:22:57 |\n| gpu[*]: (1m - 1 5m - 2 15m - 3 30m - 4 60m - 5) |",
+            )
+            .unwrap();
+        assert_eq!(rows[0].sixty_min, 5);
+    }
+
+    #[test]
+    fn test_update_applied() {
+        let running = RunningProver {
+            version: Some("0.2.4".to_owned()),
+            address: Some("aleo1abc".to_owned()),
+            uptime: Some(60),
+        };
+        let update = |version: Option<&str>, address: Option<&str>| ProverUpdate {
+            version: version.map(|v| v.to_owned()),
+            address: address.map(|a| a.to_owned()),
+            sha256: None,
+        };
+        assert!(update(Some("0.2.4"), None).applied(&running));
+        assert!(update(None, Some("aleo1abc")).applied(&running));
+        assert!(update(Some("0.2.4"), Some("aleo1abc")).applied(&running));
+        assert!(!update(Some("0.2.5"), None).applied(&running));
+        assert!(!update(Some("0.2.4"), Some("aleo1xyz")).applied(&running));
+        assert!(!update(Some("0.2.4"), None).applied(&RunningProver::default()));
+    }
+}
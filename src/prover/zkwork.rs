@@ -0,0 +1,229 @@
+// zkwork aleo prover, installed by zk-ins.sh and run as aleo.service
+
+use crate::artifacts::Artifact;
+use crate::collector::{
+    parse_prover_log, AsyncOpType, ProverInfo, INSTALL_SCRIPT, PROVER_LOG, PROVER_LOG_LINES,
+};
+use crate::error::AgentError;
+use crate::prover::{ProverBackend, ProverConfig, ProverUpdate, RunningProver};
+use crate::sh::{run_command, run_command_output, CommandOutput, SshTarget};
+
+const SERVICE: &str = "aleo.service";
+// shipped in machine.tgz next to zk-ins.sh
+const UPDATE_SCRIPT: &str = "/opt/res/machine/zk-update.sh";
+// written by zk-ins.sh and zk-update.sh
+const VERSION_FILE: &str = "/opt/aleo_prover/VERSION";
+// written by enable_service of zk-ins.sh and by zk-update.sh
+const START_SCRIPT: &str = "/opt/aleo_prover/start.sh";
+const UNIT_FILE: &str = "/etc/systemd/system/aleo.service";
+
+#[derive(Clone, Copy)]
+pub struct ZkWork;
+
+// run one stage of zk-ins.sh
+fn run_script(
+    target: &SshTarget,
+    config: &ProverConfig,
+    stage: &str,
+    timeout_seconds: u64,
+) -> AsyncOpType<CommandOutput> {
+    let target = target.clone();
+    let cmd = format!(
+        "{} {} {} {}",
+        INSTALL_SCRIPT, config.version, config.address, stage
+    );
+    Box::pin(async move { run_command_output(&target, &cmd, timeout_seconds).await })
+}
+
+fn systemctl(target: &SshTarget, action: &str, timeout_seconds: u64) -> AsyncOpType<()> {
+    let target = target.clone();
+    let cmd = format!("systemctl {} {}", action, SERVICE);
+    Box::pin(async move {
+        run_command(&target, &cmd, timeout_seconds).await?;
+        Ok(())
+    })
+}
+
+// VERSION file on the first line, then `ps -o etimes=,args=` lines of the prover
+// processes, the youngest one counts
+fn parse_running(text: &str) -> RunningProver {
+    let mut lines = text.lines();
+    let version = lines
+        .next()
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_owned());
+    let process = lines
+        .filter_map(|line| {
+            let mut args = line.split_whitespace();
+            let uptime = args.next()?.parse::<u64>().ok()?;
+            args.find(|arg| *arg == "--address")?;
+            Some((uptime, args.next()?.to_owned()))
+        })
+        .min_by_key(|(uptime, _)| *uptime);
+    RunningProver {
+        version,
+        address: process.as_ref().map(|(_, address)| address.clone()),
+        uptime: process.map(|(uptime, _)| uptime),
+    }
+}
+
+impl ProverBackend for ZkWork {
+    fn name(&self) -> &'static str {
+        "zkwork"
+    }
+
+    // zk-ins.sh and zk-update.sh download the same url when nothing was pushed
+    fn artifact(&self, version: &str) -> Artifact {
+        let name = format!("aleo_prover-v{}_full.tar.gz", version);
+        Artifact {
+            url: format!(
+                "https://gh-proxy.com/https://github.com/6block/zkwork_aleo_gpu_worker/releases/download/v{}/{}",
+                version, name
+            ),
+            name,
+        }
+    }
+
+    fn install(
+        &self,
+        target: &SshTarget,
+        config: &ProverConfig,
+        timeout_seconds: u64,
+    ) -> AsyncOpType<CommandOutput> {
+        run_script(target, config, "download_prover", timeout_seconds)
+    }
+
+    fn configure(
+        &self,
+        target: &SshTarget,
+        config: &ProverConfig,
+        timeout_seconds: u64,
+    ) -> AsyncOpType<CommandOutput> {
+        run_script(target, config, "enable_service", timeout_seconds)
+    }
+
+    fn update(
+        &self,
+        target: &SshTarget,
+        update: &ProverUpdate,
+        timeout_seconds: u64,
+    ) -> AsyncOpType<CommandOutput> {
+        let target = target.clone();
+        // zk-update.sh keeps what is installed for a -, norestart leaves the
+        // service to the agent
+        let cmd = format!(
+            "{} {} {} norestart",
+            UPDATE_SCRIPT,
+            update.version.as_deref().unwrap_or("-"),
+            update.address.as_deref().unwrap_or("-")
+        );
+        Box::pin(async move { run_command_output(&target, &cmd, timeout_seconds).await })
+    }
+
+    fn start(&self, target: &SshTarget, timeout_seconds: u64) -> AsyncOpType<()> {
+        systemctl(target, "start", timeout_seconds)
+    }
+
+    fn stop(&self, target: &SshTarget, timeout_seconds: u64) -> AsyncOpType<()> {
+        let target = target.clone();
+        // fails only when the unit exists and didn't stop
+        let cmd = format!(
+            "systemctl stop {0} || ! systemctl cat {0} > /dev/null 2>&1",
+            SERVICE
+        );
+        Box::pin(async move {
+            run_command(&target, &cmd, timeout_seconds).await?;
+            Ok(())
+        })
+    }
+
+    fn restart(&self, target: &SshTarget, timeout_seconds: u64) -> AsyncOpType<()> {
+        systemctl(target, "restart", timeout_seconds)
+    }
+
+    fn is_running(&self, target: &SshTarget, timeout_seconds: u64) -> AsyncOpType<bool> {
+        let target = target.clone();
+        let cmd = format!("systemctl is-active {}", SERVICE);
+        Box::pin(async move {
+            // is-active exits non-zero for anything but active
+            match run_command(&target, &cmd, timeout_seconds).await {
+                Ok(output) => Ok(output.trim() == "active"),
+                Err(AgentError::CommandError(_)) => Ok(false),
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    fn running(&self, target: &SshTarget, timeout_seconds: u64) -> AsyncOpType<RunningProver> {
+        let target = target.clone();
+        // ps exits non-zero when the prover isn't running
+        let cmd = format!(
+            "cat {} 2>/dev/null; echo; ps -C aleo_prover -o etimes=,args= || true",
+            VERSION_FILE
+        );
+        Box::pin(async move {
+            let output = run_command(&target, &cmd, timeout_seconds).await?;
+            Ok(parse_running(&output))
+        })
+    }
+
+    fn configured_address(
+        &self,
+        target: &SshTarget,
+        timeout_seconds: u64,
+    ) -> AsyncOpType<Option<String>> {
+        let target = target.clone();
+        // zk-update.sh reads the address to keep the same way
+        let cmd = format!(
+            "[ -f {} ] && grep -o -- '--address [^ ]*' {} | awk '{{print $2}}' || true",
+            UNIT_FILE, START_SCRIPT
+        );
+        Box::pin(async move {
+            let output = run_command(&target, &cmd, timeout_seconds).await?;
+            Ok(output
+                .lines()
+                .next()
+                .map(str::trim)
+                .filter(|address| !address.is_empty())
+                .map(|address| address.to_owned()))
+        })
+    }
+
+    fn collect_stats(
+        &self,
+        target: &SshTarget,
+        timeout_seconds: u64,
+    ) -> AsyncOpType<Vec<ProverInfo>> {
+        let target = target.clone();
+        let cmd = format!("tail -n {} {}", PROVER_LOG_LINES, PROVER_LOG);
+        let backend = *self;
+        Box::pin(async move {
+            let output = run_command_output(&target, &cmd, timeout_seconds).await?;
+            backend.parse_log(&output.stdout)
+        })
+    }
+
+    fn parse_log(&self, text: &str) -> Result<Vec<ProverInfo>, AgentError> {
+        parse_prover_log(text)
+    }
+}
+
+// test
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_running() {
+        let running = parse_running(
+            "0.2.4\n\n   4242 ./aleo_prover --address aleo1old --pool aleo.hk.zk.work:10003\n     12 ./aleo_prover --address aleo1abc --pool aleo.hk.zk.work:10003\n",
+        );
+        assert_eq!(running.version.as_deref(), Some("0.2.4"));
+        assert_eq!(running.address.as_deref(), Some("aleo1abc"));
+        assert_eq!(running.uptime, Some(12));
+
+        // installed before VERSION existed and not running
+        assert_eq!(parse_running("\n"), RunningProver::default());
+    }
+}
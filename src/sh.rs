@@ -1,84 +1,439 @@
-// shell command wrapper
+// ssh wrapper, commands and uploads run over a native async ssh session
+// every call opens its own session, dropping the returned future closes it
 
-use std::process::Command;
-use std::str;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
 
+use ipnet::Ipv4Net;
+use lazy_static::lazy_static;
 use log::{error, info};
-
-// use log::info;
+use russh::client::{self, Handle};
+use russh::keys::agent::client::AgentClient;
+use russh::keys::agent::AgentIdentity;
+use russh::keys::{load_secret_key, PrivateKeyWithHashAlg, PublicKey, PublicKeyOrCertificate};
+use russh::{ChannelMsg, Disconnect};
+use russh_sftp::client::SftpSession;
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Semaphore, SemaphorePermit};
 
 use crate::error::AgentError;
+use crate::known_hosts::check_host_key;
+
+// keep idle sessions alive while long remote scripts run without output
+const KEEPALIVE_INTERVAL: u64 = 30;
+
+const DEFAULT_USER: &str = "root";
+pub const DEFAULT_PORT: u16 = 22;
+// simultaneous ssh sessions across all commands, SSH_MAX_SESSIONS overrides it
+const DEFAULT_MAX_SESSIONS: usize = 128;
+
+lazy_static! {
+    static ref SESSIONS: Semaphore = Semaphore::new(max_sessions());
+}
+
+fn max_sessions() -> usize {
+    std::env::var("SSH_MAX_SESSIONS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(DEFAULT_MAX_SESSIONS)
+}
+
+// how to authenticate an ssh session
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Credential {
+    Password {
+        password: String,
+    },
+    // private key file on the agent host, optionally encrypted
+    KeyFile {
+        path: String,
+        #[serde(default)]
+        passphrase: Option<String>,
+    },
+    // ssh-agent socket, SSH_AUTH_SOCK when not given
+    Agent {
+        #[serde(default)]
+        socket: Option<String>,
+    },
+}
+
+// keep secrets out of the logs
+impl fmt::Debug for Credential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credential::Password { .. } => write!(f, "Password"),
+            Credential::KeyFile { path, .. } => write!(f, "KeyFile({})", path),
+            Credential::Agent { socket } => write!(f, "Agent({:?})", socket),
+        }
+    }
+}
+
+impl Credential {
+    fn validate(&self) -> Result<(), AgentError> {
+        let empty = match self {
+            Credential::Password { password } => password.is_empty(),
+            Credential::KeyFile { path, .. } => path.is_empty(),
+            Credential::Agent { socket } => socket.as_deref() == Some(""),
+        };
+        if empty {
+            return Err(AgentError::ProtocolError(format!(
+                "incomplete credential: {:?}",
+                self
+            )));
+        }
+        Ok(())
+    }
+}
+
+// one host to log in to
+#[derive(Debug, Clone, PartialEq)]
+pub struct SshTarget {
+    pub ip: String,
+    pub port: u16,
+    pub user: String,
+    pub credential: Credential,
+}
+
+impl fmt::Display for SshTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}:{}", self.user, self.ip, self.port)
+    }
+}
+
+fn default_user() -> String {
+    DEFAULT_USER.to_owned()
+}
+
+fn default_port() -> u16 {
+    DEFAULT_PORT
+}
+
+// login settings of a command, overrides change them for single hosts or subnets
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    #[serde(default = "default_user")]
+    pub user: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub credential: Credential,
+    #[serde(default)]
+    pub overrides: Vec<AuthOverride>,
+}
 
-// run scp command to cp spcified file to remote
-pub fn run_scp(
-    ip: &str,
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthOverride {
+    // single ip or cidr, e.g. 192.168.1.7 or 192.168.1.0/28
+    pub hosts: String,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub credential: Option<Credential>,
+}
+
+impl AuthOverride {
+    fn network(&self) -> Result<Ipv4Net, AgentError> {
+        if let Ok(ip) = self.hosts.parse::<Ipv4Addr>() {
+            return Ok(Ipv4Net::from(ip));
+        }
+        self.hosts.parse::<Ipv4Net>().map_err(|_| {
+            AgentError::ProtocolError(format!("invalid override hosts: {:?}", self.hosts))
+        })
+    }
+}
+
+impl AuthConfig {
+    pub fn password(password: &str) -> Self {
+        AuthConfig {
+            user: default_user(),
+            port: default_port(),
+            credential: Credential::Password {
+                password: password.to_owned(),
+            },
+            overrides: vec![],
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), AgentError> {
+        self.credential.validate()?;
+        for o in &self.overrides {
+            o.network()?;
+            if let Some(credential) = &o.credential {
+                credential.validate()?;
+            }
+        }
+        Ok(())
+    }
+
+    // login settings for ip, the most specific matching override wins
+    pub fn target(&self, ip: &str) -> SshTarget {
+        let mut target = SshTarget {
+            ip: ip.to_owned(),
+            port: self.port,
+            user: self.user.clone(),
+            credential: self.credential.clone(),
+        };
+
+        let Ok(addr) = ip.parse::<Ipv4Addr>() else {
+            return target;
+        };
+        let matched = self
+            .overrides
+            .iter()
+            .filter_map(|o| o.network().ok().map(|net| (net, o)))
+            .filter(|(net, _)| net.contains(&addr))
+            .max_by_key(|(net, _)| net.prefix_len());
+        if let Some((_, o)) = matched {
+            if let Some(user) = &o.user {
+                target.user = user.clone();
+            }
+            if let Some(port) = o.port {
+                target.port = port;
+            }
+            if let Some(credential) = &o.credential {
+                target.credential = credential.clone();
+            }
+        }
+
+        target
+    }
+}
+
+struct Client {
+    host: String,
     port: u16,
-    user: &str,
-    password: &str,
+}
+
+impl client::Handler for Client {
+    type Error = AgentError;
+
+    // trust on first use, see known_hosts
+    async fn check_server_key(
+        &mut self,
+        server_public_key: &PublicKeyOrCertificate,
+    ) -> Result<bool, Self::Error> {
+        let key = match server_public_key {
+            PublicKeyOrCertificate::PublicKey { key, .. } => key.clone(),
+            PublicKeyOrCertificate::Certificate(cert) => PublicKey::from(cert.public_key().clone()),
+        };
+        check_host_key(&self.host, self.port, &key)?;
+        Ok(true)
+    }
+}
+
+async fn connect(target: &SshTarget) -> Result<Handle<Client>, AgentError> {
+    let config = client::Config {
+        keepalive_interval: Some(Duration::from_secs(KEEPALIVE_INTERVAL)),
+        nodelay: true,
+        ..Default::default()
+    };
+
+    let client = Client {
+        host: target.ip.clone(),
+        port: target.port,
+    };
+    let mut session =
+        client::connect(Arc::new(config), (target.ip.as_str(), target.port), client).await?;
+    let success = match &target.credential {
+        Credential::Password { password } => session
+            .authenticate_password(&target.user, password)
+            .await?
+            .success(),
+        Credential::KeyFile { path, passphrase } => {
+            let key = load_secret_key(path, passphrase.as_deref())?;
+            let hash_alg = session.best_supported_rsa_hash().await?.flatten();
+            session
+                .authenticate_publickey(
+                    &target.user,
+                    PrivateKeyWithHashAlg::new(Arc::new(key), hash_alg),
+                )
+                .await?
+                .success()
+        }
+        Credential::Agent { socket } => authenticate_agent(&mut session, target, socket).await?,
+    };
+
+    if !success {
+        return Err(AgentError::AuthError(target.to_string()));
+    }
+
+    Ok(session)
+}
+
+// try every identity of the ssh agent until one is accepted
+async fn authenticate_agent(
+    session: &mut Handle<Client>,
+    target: &SshTarget,
+    socket: &Option<String>,
+) -> Result<bool, AgentError> {
+    let mut agent = match socket {
+        Some(socket) => AgentClient::connect_uds(socket).await?,
+        None => AgentClient::connect_env().await?,
+    };
+
+    let hash_alg = session.best_supported_rsa_hash().await?.flatten();
+    for identity in agent.request_identities().await? {
+        let AgentIdentity::PublicKey { key, .. } = identity else {
+            continue;
+        };
+        let auth = session
+            .authenticate_publickey_with(&target.user, key, hash_alg, &mut agent)
+            .await
+            .map_err(|e| AgentError::SshError(e.to_string()))?;
+        if auth.success() {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+// wait for a free session slot, the wait doesn't count against the caller's timeout
+async fn session_permit() -> Result<SemaphorePermit<'static>, AgentError> {
+    SESSIONS
+        .acquire()
+        .await
+        .map_err(|e| AgentError::SshError(e.to_string()))
+}
+
+async fn disconnect(session: &Handle<Client>) {
+    let _ = session
+        .disconnect(Disconnect::ByApplication, "", "English")
+        .await;
+}
+
+// bound an ssh operation by the caller's timeout
+async fn with_timeout<T, F>(timeout_seconds: u64, op: F) -> Result<T, AgentError>
+where
+    F: std::future::Future<Output = Result<T, AgentError>>,
+{
+    tokio::time::timeout(Duration::from_secs(timeout_seconds), op)
+        .await
+        .map_err(|_| AgentError::Timeout(timeout_seconds))?
+}
+
+// copy spcified local file to remote over sftp
+pub async fn run_scp(
+    target: &SshTarget,
     local_file: &str,
     remote_file: &str,
     timeout_seconds: u64,
 ) -> Result<(), AgentError> {
-    let output = Command::new("timeout")
-        .arg(timeout_seconds.to_string())
-        .arg("sshpass")
-        .arg("-p")
-        .arg(password)
-        .arg("scp")
-        .arg("-P")
-        .arg(port.to_string())
-        .arg("-o")
-        .arg("StrictHostKeyChecking=no")
-        .arg(local_file)
-        .arg(format!("{}@{}:{}", user, ip, remote_file))
-        .output()?;
-
-    if output.status.success() {
-        info!("scp success");
-        return Ok(());
-    } else {
-        let stderr = str::from_utf8(&output.stderr)?;
-        error!("scp error:{}", stderr);
-        return Err(AgentError::CommandError(stderr.to_owned()));
+    let _permit = session_permit().await?;
+    let result = with_timeout(timeout_seconds, async {
+        let session = connect(target).await?;
+        let result = upload(&session, local_file, remote_file).await;
+        disconnect(&session).await;
+        result
+    })
+    .await;
+
+    match &result {
+        Ok(_) => info!("scp success"),
+        Err(e) => error!("scp error:{}", e),
     }
+    result
 }
 
-pub fn run_command(
-    ip: &str,
-    port: u16,
-    user: &str,
-    password: &str,
+async fn upload(
+    session: &Handle<Client>,
+    local_file: &str,
+    remote_file: &str,
+) -> Result<(), AgentError> {
+    let channel = session.channel_open_session().await?;
+    channel.request_subsystem(true, "sftp").await?;
+    let sftp = SftpSession::new(channel.into_stream()).await?;
+
+    let mut local = tokio::fs::File::open(local_file).await?;
+    let mut remote = sftp.create(remote_file).await?;
+    tokio::io::copy(&mut local, &mut remote).await?;
+    remote.shutdown().await?;
+    sftp.close().await?;
+
+    Ok(())
+}
+
+// output of a successful remote command
+#[derive(Debug, Default, Clone)]
+pub struct CommandOutput {
+    pub stdout: String,
+    pub stderr: String,
+}
+
+pub async fn run_command(
+    target: &SshTarget,
     command: &str,
     timeout_seconds: u64,
 ) -> Result<String, AgentError> {
+    let output = run_command_output(target, command, timeout_seconds).await?;
+    info!("stdout: {}", output.stdout);
+    Ok(output.stdout)
+}
+
+// same as run_command, but keep stderr of successful commands too
+pub async fn run_command_output(
+    target: &SshTarget,
+    command: &str,
+    timeout_seconds: u64,
+) -> Result<CommandOutput, AgentError> {
     //info!("cmd: {}", command);
-    let output = Command::new("timeout")
-        .arg(timeout_seconds.to_string())
-        .arg("sshpass")
-        .arg("-p")
-        .arg(password)
-        .arg("ssh")
-        .arg("-o")
-        .arg("StrictHostKeyChecking=no")
-        .arg("-p")
-        .arg(port.to_string())
-        .arg(format!("{}@{}", user, ip))
-        .arg(command)
-        .output()?;
-
-    //info!("cmd status: {}", output.status);
-
-    if output.status.success() {
-        let stdout = str::from_utf8(&output.stdout)?;
-        info!("stdout: {}", stdout);
-        return Ok(stdout.to_owned());
+    let _permit = session_permit().await?;
+    with_timeout(timeout_seconds, async {
+        let session = connect(target).await?;
+        let result = exec(&session, command).await;
+        disconnect(&session).await;
+        result
+    })
+    .await
+}
+
+async fn exec(session: &Handle<Client>, command: &str) -> Result<CommandOutput, AgentError> {
+    let mut channel = session.channel_open_session().await?;
+    channel.exec(true, command).await?;
+
+    let mut stdout = vec![];
+    let mut stderr = vec![];
+    let mut exit_status = None;
+    // there might be more data after the exit status, read until the channel closes
+    while let Some(msg) = channel.wait().await {
+        match msg {
+            ChannelMsg::Data { data } => stdout.extend_from_slice(&data),
+            ChannelMsg::ExtendedData { data, ext: 1 } => stderr.extend_from_slice(&data),
+            ChannelMsg::ExitStatus { exit_status: code } => exit_status = Some(code),
+            _ => {}
+        }
+    }
+
+    //info!("cmd status: {:?}", exit_status);
+
+    // a stray byte in a log line mustn't fail the whole command
+    if exit_status == Some(0) {
+        Ok(CommandOutput {
+            stdout: String::from_utf8_lossy(&stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        })
     } else {
-        let stderr = str::from_utf8(&output.stderr)?;
         //error!("cmd error:{}", stderr);
-        return Err(AgentError::CommandError(stderr.to_owned()));
+        Err(AgentError::CommandError(
+            String::from_utf8_lossy(&stderr).into_owned(),
+        ))
     }
 }
 
+// last lines of a command output, for progress reports
+pub fn tail_lines(text: &str, lines: usize) -> String {
+    let all: Vec<&str> = text.lines().collect();
+    all[all.len().saturating_sub(lines)..].join("\n")
+}
+
 // Test
 #[cfg(test)]
 mod tests {
@@ -93,21 +448,84 @@ mod tests {
     fn init_logger() {
         INIT.call_once(|| {
             std::env::set_var("RUST_LOG", "info");
-            env_logger::init();
+            let _ = env_logger::try_init();
         });
     }
 
     #[test]
+    #[ignore = "needs a reachable ssh host"]
     fn test_run_command() {
         init_logger();
 
-        let ip = "45.144.136.65";
-        let port = 6002;
-        let password = "ylkj..";
+        let target = SshTarget {
+            port: 6002,
+            user: "ylkj09".to_owned(),
+            ..AuthConfig::password("ylkj..").target("45.144.136.65")
+        };
         let command = "ls";
-        let user = "ylkj09";
-        let result = run_command(ip, port, user, password, command, 5);
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let result = rt.block_on(run_command(&target, command, 5));
         //info!("{:?}", result);
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_tail_lines() {
+        assert_eq!(tail_lines("a\nb\nc\n", 2), "b\nc");
+        assert_eq!(tail_lines("a", 5), "a");
+        assert_eq!(tail_lines("", 5), "");
+    }
+
+    #[test]
+    fn test_auth_config_target() {
+        let auth: AuthConfig = serde_json::from_str(
+            r#"{
+                "credential": {"type": "password", "password": "x"},
+                "overrides": [
+                    {"hosts": "192.168.1.0/24", "user": "ubuntu"},
+                    {"hosts": "192.168.1.0/28", "port": 2222,
+                     "credential": {"type": "key_file", "path": "/root/.ssh/id_ed25519"}},
+                    {"hosts": "192.168.1.200", "credential": {"type": "agent"}}
+                ]
+            }"#,
+        )
+        .unwrap();
+        auth.validate().unwrap();
+
+        let target = auth.target("10.0.0.1");
+        assert_eq!(target, AuthConfig::password("x").target("10.0.0.1"));
+        assert_eq!(target.to_string(), "root@10.0.0.1:22");
+
+        let target = auth.target("192.168.1.100");
+        assert_eq!(target.user, "ubuntu");
+        assert_eq!(target.port, 22);
+
+        // the /28 wins over the /24, fields it doesn't set keep the defaults
+        let target = auth.target("192.168.1.3");
+        assert_eq!(target.user, "root");
+        assert_eq!(target.port, 2222);
+        assert!(matches!(target.credential, Credential::KeyFile { .. }));
+
+        let target = auth.target("192.168.1.200");
+        assert_eq!(target.credential, Credential::Agent { socket: None });
+    }
+
+    #[test]
+    fn test_auth_config_validate() {
+        let auth: AuthConfig = serde_json::from_str(
+            r#"{"credential": {"type": "password", "password": "x"},
+                "overrides": [{"hosts": "192.168.1.0/33"}]}"#,
+        )
+        .unwrap();
+        assert!(auth.validate().is_err());
+
+        let auth: AuthConfig =
+            serde_json::from_str(r#"{"credential": {"type": "key_file", "path": ""}}"#).unwrap();
+        assert!(auth.validate().is_err());
+
+        let result: Result<AuthConfig, _> = serde_json::from_str(
+            r#"{"credential": {"type": "password", "password": "x", "y": 1}}"#,
+        );
+        assert!(result.is_err());
+    }
 }
@@ -1,29 +1,42 @@
-use std::vec;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
 use futures_util::sink::SinkExt;
+use futures_util::stream::SplitSink;
 use futures_util::StreamExt;
 use log::error;
 use log::info;
-//use serde::{Deserialize, Serialize};
-use serde_json::json;
-use serde_json::Value;
 use tokio::net::TcpStream;
+use tokio::select;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 use tokio_tungstenite::connect_async;
 use tokio_tungstenite::tungstenite::protocol::Message;
 use tokio_tungstenite::MaybeTlsStream;
 use tokio_tungstenite::WebSocketStream;
+use tokio_util::sync::CancellationToken;
 
-use crate::collector::{batch_scan, deploy_to_ip};
+use crate::collector::{
+    batch_deploy, batch_plan, batch_scan, plan_deploy, plan_update, query_ip, reboot_ip,
+    reboot_prover, reporting, run_batch, update_ip, DeployProgress, HostPlan, MachineInfo,
+    ResultSender, PROVER_RECOVERY_SECONDS, QUERY_TIMEOUT_SECONDS, REBOOT_RECOVERY_SECONDS,
+};
 use crate::error::AgentError;
+use crate::known_hosts::{self, HostKey, HostKeyChange};
+use crate::protocol::{
+    parse_command, BatchDone, CancelResult, Command, ControlResult, DeployDone, DeployRequest,
+    DeployResult, ErrorCode, ErrorReply, Event, HostKeyTrusted, QueryRequest, RebootRequest,
+    RestartProverRequest, ScanDone, ScanRequest, UpdateRequest, UpdateResult, PROTOCOL_VERSION,
+};
+use crate::prover::RunningProver;
 
 type WsType = WebSocketStream<MaybeTlsStream<TcpStream>>;
 
-// #[derive(Serialize, Deserialize, Debug)]
-// struct BatchConfig {
-//     ips: Vec<String>,
-//     pools: Vec<PoolConfig>,
-//     mode: String,
-// }
+type InFlight = Arc<Mutex<HashMap<String, CancellationToken>>>;
+
+// frames waiting for the writer task, senders wait when it's full
+const OUTBOUND_QUEUE_SIZE: usize = 256;
 
 pub async fn connect_to_websocket(
     url: &str,
@@ -38,204 +51,640 @@ pub async fn connect_to_websocket(
     }
 }
 
-pub async fn send_message(
-    ws_stream: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
-    message: &str,
-) -> Result<(), AgentError> {
-    // info!("send: {}", message);
-    ws_stream
-        .send(Message::Text(message.to_string()))
-        .await
-        .map_err(|e| AgentError::WebSocketError(e.to_string()))
+// handle to the writer task, tagging every event with the request id
+#[derive(Clone)]
+pub struct Responder {
+    id: String,
+    // protocol version the command was sent in
+    version: u32,
+    tx: mpsc::Sender<String>,
 }
 
-pub async fn receive_message(
-    ws_stream: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
-    runtime_handle: &tokio::runtime::Handle,
-) {
+impl Responder {
+    pub fn new(id: &str, version: u32, tx: mpsc::Sender<String>) -> Self {
+        Responder {
+            id: id.to_owned(),
+            version,
+            tx,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub async fn send(&self, event: &Event) -> Result<(), AgentError> {
+        let message = event.to_message(Some(&self.id), self.version)?;
+        self.tx
+            .send(message)
+            .await
+            .map_err(|_| AgentError::WebSocketError("connection closed".to_owned()))
+    }
+}
+
+// forward queued messages to the websocket until the connection or channel closes
+async fn write_loop(mut sink: SplitSink<WsType, Message>, mut rx: mpsc::Receiver<String>) {
+    while let Some(message) = rx.recv().await {
+        if let Err(e) = sink.send(Message::Text(message)).await {
+            error!("Failed to send message: {}", e);
+            return;
+        }
+    }
+}
+
+// read commands until the connection breaks, every command runs in its own task
+pub async fn receive_message(ws_stream: WsType, runtime_handle: &tokio::runtime::Handle) {
+    let (sink, mut stream) = ws_stream.split();
+    let (tx, rx) = mpsc::channel::<String>(OUTBOUND_QUEUE_SIZE);
+    let writer = runtime_handle.spawn(write_loop(sink, rx));
+    // cancellation tokens of running commands by request id, of this connection only
+    let in_flight: InFlight = Arc::default();
+    let host_keys = runtime_handle.spawn(forward_host_key_changes(tx.clone()));
+
     loop {
         // if failed to receive message, return to reconnect
-        let msg = match ws_stream.next().await {
+        let msg = match stream.next().await {
             Some(Ok(msg)) => msg,
             Some(Err(e)) => {
                 error!("Failed to receive message: {}", e);
-                return;
+                break;
             }
             None => {
                 error!("Failed to receive message");
-                return;
+                break;
             }
         };
 
-        //.expect("Failed to receive message")
-        //.unwrap();
+        if writer.is_finished() {
+            error!("Writer stopped, reconnecting");
+            break;
+        }
+
         match msg {
             Message::Text(text) => {
                 info!("Received message: {}", text);
-                // parser text into json, ignore error
-                let json: Value = serde_json::from_str(&text).unwrap_or(json!({}));
-                match json["name"].as_str() {
-                    Some("scan") => {
-                        info!("Received scan command");
-                        let ip = json["data"]["ip"].as_str().unwrap_or("");
-                        let pwd = json["data"]["pwd"].as_str().unwrap_or("");
-
-                        if ip.is_empty() || pwd.is_empty() {
-                            error!("IP or PWD is empty");
-                        } else {
-                            match process_scan(ws_stream, ip, pwd, runtime_handle).await {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    error!("Failed to process scan: {}", e);
-                                    return;
+                match parse_command(&text) {
+                    Ok(request) => {
+                        info!(
+                            "Received {} command, id: {}",
+                            request.command.name(),
+                            request.id
+                        );
+                        let responder = Responder::new(&request.id, request.version, tx.clone());
+                        if let Command::Cancel(req) = &request.command {
+                            let found = match in_flight.lock().unwrap().get(&req.request_id) {
+                                Some(token) => {
+                                    token.cancel();
+                                    true
                                 }
+                                None => false,
+                            };
+                            let result = CancelResult {
+                                request_id: req.request_id.clone(),
+                                found,
+                            };
+                            if responder.send(&Event::CancelResult(result)).await.is_err() {
+                                break;
                             }
+                            continue;
                         }
-                    }
-                    Some("deploy") => {
-                        info!("Received deploy command");
-                        let ip = json["data"]["ip"].as_str().unwrap_or("");
-                        let pwd = json["data"]["pwd"].as_str().unwrap_or("");
-                        let ver = json["data"]["ver"].as_str().unwrap_or("");
-                        let addr = json["data"]["addr"].as_str().unwrap_or("");
-
-                        if ip.is_empty() || pwd.is_empty() || ver.is_empty() || addr.is_empty() {
-                            error!("IP or PWD is empty");
-                        } else {
-                            match process_deploy(ws_stream, ip, pwd, ver, addr, runtime_handle)
-                                .await
-                            {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    error!("Failed to process scan: {}", e);
-                                    return;
-                                }
+
+                        let cancel = CancellationToken::new();
+                        let duplicate = {
+                            let mut in_flight = in_flight.lock().unwrap();
+                            if in_flight.contains_key(&request.id) {
+                                true
+                            } else {
+                                in_flight.insert(request.id.clone(), cancel.clone());
+                                false
+                            }
+                        };
+                        if duplicate {
+                            let reply = ErrorReply::new(
+                                ErrorCode::DuplicateRequest,
+                                format!("request {} is still running", request.id),
+                                Some(request.command.name()),
+                            );
+                            if responder.send(&Event::Error(reply)).await.is_err() {
+                                break;
                             }
+                            continue;
                         }
+
+                        let in_flight = in_flight.clone();
+                        let runtime = runtime_handle.clone();
+                        runtime_handle.spawn(async move {
+                            execute(request.command, &responder, runtime, cancel).await;
+                            in_flight.lock().unwrap().remove(responder.id());
+                        });
                     }
-                    Some("query") => {
-                        info!("Received query command");
-                        let ip = json["data"].as_str().unwrap_or("");
-                        if ip.is_empty() {
-                            error!("IP is empty");
-                        } else {
-                            // query machine
-                            // use watching interface
-                            let _ips = vec![ip.to_string()];
-                            // let result = lcd_core::watching(runtime_handle.clone(), ips, 3)
-                            //     .await
-                            //     // ignore error
-                            //     .unwrap_or(vec![]);
-
-                            // let message = serde_json::json!({
-                            //     "name": "query_result",
-                            //     "data": if result.len() > 0 {
-                            //         serde_json::to_string(&result[0]).unwrap()
-                            //     } else {
-                            //         "{}".to_string()
-                            //     }
-                            // });
-
-                            // match send_message(ws_stream, &message.to_string()).await {
-                            //     Ok(_) => {}
-                            //     Err(e) => {
-                            //         error!("Failed to send query result message: {}", e);
-                            //         return;
-                            //     }
-                            // }
+                    Err(rejected) => {
+                        error!("Rejected message: {}", rejected.reply.message);
+                        let event = Event::Error(rejected.reply);
+                        match event.to_message(rejected.id.as_deref(), rejected.version) {
+                            Ok(message) => {
+                                if tx.send(message).await.is_err() {
+                                    break;
+                                }
+                            }
+                            Err(e) => error!("Failed to encode error message: {}", e),
                         }
                     }
-                    _ => {
-                        error!("Received unexpected message type");
-                    }
                 }
             }
             // handle disconnect
             Message::Close(_) => {
                 info!("Received close message");
-                return;
+                break;
             }
+            // ping/pong are answered by tungstenite
+            Message::Ping(_) | Message::Pong(_) => {}
             _ => error!("Received unexpected message type"),
         }
     }
+
+    // commands don't outlive their connection, nothing could deliver their results
+    // or cancel them after a reconnect. The server sends them again, a deploy
+    // resumes at the stage it was cancelled in
+    for (id, token) in in_flight.lock().unwrap().iter() {
+        info!("Cancelling {} after the connection dropped", id);
+        token.cancel();
+    }
+    host_keys.abort();
+    writer.abort();
+}
+
+// announce host key changes detected by any command, the ones still waiting for
+// approval first, they may have been detected while no connection was up
+async fn forward_host_key_changes(tx: mpsc::Sender<String>) {
+    let (pending, mut changes) = known_hosts::subscribe();
+    for change in pending {
+        if !send_host_key_change(&tx, change).await {
+            return;
+        }
+    }
+    loop {
+        let change = match changes.recv().await {
+            Ok(change) => change,
+            Err(RecvError::Lagged(n)) => {
+                error!("Missed {} host key changes", n);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        if !send_host_key_change(&tx, change).await {
+            return;
+        }
+    }
+}
+
+// false once the connection is gone
+async fn send_host_key_change(tx: &mpsc::Sender<String>, change: HostKeyChange) -> bool {
+    match Event::HostKeyChanged(change).to_message(None, PROTOCOL_VERSION) {
+        Ok(message) => tx.send(message).await.is_ok(),
+        Err(e) => {
+            error!("Failed to encode host key change: {}", e);
+            true
+        }
+    }
+}
+
+// run one command to completion, failures are reported as error events
+async fn execute(
+    command: Command,
+    responder: &Responder,
+    runtime_handle: tokio::runtime::Handle,
+    cancel: CancellationToken,
+) {
+    let result = match &command {
+        Command::Scan(req) => process_scan(responder, req, &runtime_handle, &cancel).await,
+        Command::Deploy(req) => process_deploy(responder, req, &runtime_handle, &cancel).await,
+        Command::Reboot(req) => process_reboot(responder, req, &runtime_handle, &cancel).await,
+        Command::RestartProver(req) => {
+            process_restart_prover(responder, req, &runtime_handle, &cancel).await
+        }
+        Command::Update(req) => process_update(responder, req, &runtime_handle, &cancel).await,
+        Command::Query(req) => process_query(responder, req, &runtime_handle, &cancel).await,
+        // handled by the reader
+        Command::Cancel(_req) => Ok(()),
+        Command::ApproveHostKey(req) => {
+            let result = known_hosts::approve_host_key(&req.host, req.port, &req.fingerprint);
+            process_host_key(responder, &req.host, req.port, result).await
+        }
+        Command::PinHostKey(req) => {
+            let result = known_hosts::pin_host_key(&req.host, req.port, &req.key);
+            process_host_key(responder, &req.host, req.port, result).await
+        }
+    };
+
+    match result {
+        Ok(_) => {}
+        Err(AgentError::Cancelled(report)) => {
+            info!("{} {} cancelled", command.name(), responder.id());
+            if let Err(e) = responder.send(&Event::Cancelled(report)).await {
+                error!("Failed to send cancelled message: {}", e);
+            }
+        }
+        // connection is gone, nobody to report to
+        Err(AgentError::WebSocketError(e)) => {
+            error!(
+                "Failed to process {} {}: {}",
+                command.name(),
+                responder.id(),
+                e
+            );
+        }
+        Err(e) => {
+            error!(
+                "Failed to process {} {}: {}",
+                command.name(),
+                responder.id(),
+                e
+            );
+            let reply = ErrorReply::new(
+                ErrorCode::CommandFailed,
+                e.to_string(),
+                Some(command.name()),
+            );
+            if let Err(e) = responder.send(&Event::Error(reply)).await {
+                error!("Failed to send error message: {}", e);
+            }
+        }
+    }
+}
+
+async fn process_host_key(
+    responder: &Responder,
+    host: &str,
+    port: u16,
+    result: Result<HostKey, AgentError>,
+) -> Result<(), AgentError> {
+    let entry = result?;
+    let trusted = HostKeyTrusted {
+        host: host.to_owned(),
+        port,
+        fingerprint: entry.fingerprint()?,
+        pinned: entry.pinned,
+    };
+    responder.send(&Event::HostKeyTrusted(trusted)).await
 }
 
 async fn process_scan(
-    ws_stream: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
-    ip: &str,
-    pwd: &str,
+    responder: &Responder,
+    req: &ScanRequest,
     runtime_handle: &tokio::runtime::Handle,
+    cancel: &CancellationToken,
 ) -> Result<(), AgentError> {
-    let machines = batch_scan(ip, pwd, runtime_handle).await?;
-
-    // split machines into multiple messages, 10 machines per message
-    let mut start = 0;
-    let mut end = 10;
-    while start < machines.len() {
-        end = std::cmp::min(end, machines.len());
-        let message = serde_json::json!({
-            "name": "scan_result",
-            "data": serde_json::to_string(&machines[start..end])?,
-        });
+    let targets = req.targets();
+    let hosts = targets.expand()?.len();
+    let (found, found_rx) = mpsc::unbounded_channel();
+    let sender = runtime_handle.spawn(send_machines(responder.clone(), found_rx));
+    let report = batch_scan(
+        &targets,
+        &req.auth(),
+        &req.prover(),
+        &found,
+        req.concurrency(),
+        runtime_handle,
+        cancel,
+    )
+    .await;
+    drop(found);
+    // every scan_result goes out before scan_done or cancelled
+    let chunks = sender.await.unwrap_or_default();
+    let report = report?;
 
-        // info!("send: {}", message.to_string());
-        send_message(ws_stream, &message.to_string()).await?;
-        info!("send scan result message {} {}", start, end);
+    // machines carry their own status, scan_done sums them up per outcome
+    let done = ScanDone {
+        hosts,
+        machines: report.machines.len(),
+        chunks,
+        unreachable: report.unreachable,
+        auth_failed: report.auth_failed,
+        failed: report.failed,
+    };
+    responder.send(&Event::ScanDone(done)).await
+}
 
-        start = end;
-        end += 10;
+// send machines as they are collected, up to 10 per message, returns the number of messages
+async fn send_machines(
+    responder: Responder,
+    mut found: mpsc::UnboundedReceiver<MachineInfo>,
+) -> usize {
+    let mut chunks = 0;
+    while let Some(machine) = found.recv().await {
+        let mut chunk = vec![machine];
+        while chunk.len() < 10 {
+            match found.try_recv() {
+                Ok(machine) => chunk.push(machine),
+                Err(_) => break,
+            }
+        }
+        let len = chunk.len();
+        match responder.send(&Event::ScanResult(chunk)).await {
+            Ok(_) => {
+                info!("send scan result message {} with {} machines", chunks, len);
+                chunks += 1;
+            }
+            Err(e) => error!("Failed to send scan result: {}", e),
+        }
     }
+    chunks
+}
 
-    Ok(())
+// send an event per host as soon as it is done, ends when the batch drops the sender
+fn forward_results<T: Send + 'static>(
+    responder: &Responder,
+    runtime_handle: &tokio::runtime::Handle,
+    event: fn(String, Result<T, String>) -> Event,
+) -> (ResultSender<T>, JoinHandle<()>) {
+    let (results, mut results_rx) = mpsc::unbounded_channel();
+    let responder = responder.clone();
+    let forwarder = runtime_handle.spawn(async move {
+        while let Some((ip, res)) = results_rx.recv().await {
+            if let Err(e) = responder.send(&event(ip, res)).await {
+                error!("Failed to send result: {}", e);
+            }
+        }
+    });
+    (results, forwarder)
+}
+
+// succeeded and failed hosts of a finished batch
+fn summarize<T>(results: &[(String, Result<T, AgentError>)]) -> BatchDone {
+    let failed = results.iter().filter(|(_, res)| res.is_err()).count();
+    BatchDone {
+        hosts: results.len(),
+        succeeded: results.len() - failed,
+        failed,
+    }
 }
 
 // cp shell script to remote
 // execute shell script
 async fn process_deploy(
-    ws_stream: &mut WsType,
-    ip: &str,
-    pwd: &str,
-    ver: &str,
-    addr: &str,
+    responder: &Responder,
+    req: &DeployRequest,
+    runtime_handle: &tokio::runtime::Handle,
+    cancel: &CancellationToken,
+) -> Result<(), AgentError> {
+    if req.dry_run {
+        let config = req.prover_config();
+        let targets = req.targets();
+        let mut plans = batch_plan(
+            &targets,
+            &req.auth(),
+            &req.prover(),
+            |installed| plan_deploy(installed, &config),
+            req.concurrency(),
+            runtime_handle,
+            cancel,
+        )
+        .await?;
+        if let Some(rollout) = &req.rollout {
+            let waves = rollout.waves(&targets.ips()?);
+            for plan in &mut plans {
+                plan.wave = waves.iter().position(|wave| wave.contains(&plan.ip));
+            }
+        }
+        return send_plans(responder, plans).await;
+    }
+
+    // forward stage events and host results as they happen, ends when the deploy
+    // drops both senders. A host's progress is sent before its result
+    let (progress, mut progress_rx) = mpsc::unbounded_channel::<DeployProgress>();
+    let (results, mut results_rx) = mpsc::unbounded_channel();
+    let forwarder = {
+        let responder = responder.clone();
+        runtime_handle.spawn(async move {
+            loop {
+                let event = select! {
+                    biased;
+                    Some(event) = progress_rx.recv() => Event::DeployProgress(event),
+                    Some((ip, res)) = results_rx.recv() => deploy_result(ip, res),
+                    else => break,
+                };
+                if let Err(e) = responder.send(&event).await {
+                    error!("Failed to send deploy event: {}", e);
+                }
+            }
+        })
+    };
+
+    let report = batch_deploy(
+        &req.targets(),
+        &req.auth(),
+        &req.prover(),
+        &req.prover_config(),
+        req.rollout.as_ref(),
+        &progress,
+        &results,
+        req.concurrency(),
+        runtime_handle,
+        cancel,
+    )
+    .await;
+    drop(progress);
+    drop(results);
+    // progress and result frames go out before the final frame
+    let _ = forwarder.await;
+    let report = report?;
+
+    let mut summary = summarize(&report.results);
+    summary.hosts += report.skipped.len();
+    let done = DeployDone {
+        summary,
+        waves: report.waves,
+        skipped: report.skipped,
+    };
+    responder.send(&Event::DeployDone(done)).await
+}
+
+fn deploy_result(ip: String, res: Result<(), String>) -> Event {
+    if let Err(e) = &res {
+        error!("deploy to {} failed: {}", ip, e);
+    }
+    Event::DeployResult(DeployResult {
+        ip,
+        ok: res.is_ok(),
+        error: res.err(),
+    })
+}
+
+// dry run results in chunks like scan results, then the summary
+async fn send_plans(responder: &Responder, plans: Vec<HostPlan>) -> Result<(), AgentError> {
+    let mut done = BatchDone {
+        hosts: plans.len(),
+        ..BatchDone::default()
+    };
+    for plan in &plans {
+        if plan.ready {
+            done.succeeded += 1;
+        } else {
+            done.failed += 1;
+        }
+    }
+    for chunk in plans.chunks(10) {
+        responder.send(&Event::PlanResult(chunk.to_vec())).await?;
+    }
+    responder.send(&Event::PlanDone(done)).await
+}
+
+async fn process_query(
+    responder: &Responder,
+    req: &QueryRequest,
     runtime_handle: &tokio::runtime::Handle,
+    cancel: &CancellationToken,
 ) -> Result<(), AgentError> {
-    deploy_to_ip(ip, pwd, ver, addr, 10).await?;
-    Ok(())
+    let auth = req.auth();
+    let backend = req.prover();
+    // a batch of one, so the query can be cancelled like the others
+    let mut results = run_batch(
+        vec![req.ip.clone()],
+        |ip| query_ip(&auth.target(ip), &backend, QUERY_TIMEOUT_SECONDS),
+        1,
+        runtime_handle,
+        cancel,
+    )
+    .await?;
+
+    // query_ip reports failures in the machine status
+    let (_, info) = results.remove(0);
+    responder.send(&Event::QueryResult(Box::new(info?))).await
 }
 
-// update machine specified pkg
+async fn process_reboot(
+    responder: &Responder,
+    req: &RebootRequest,
+    runtime_handle: &tokio::runtime::Handle,
+    cancel: &CancellationToken,
+) -> Result<(), AgentError> {
+    let auth = req.auth();
+    let (results, forwarder) = forward_results(responder, runtime_handle, |ip, res| {
+        Event::RebootResult(control_result(ip, res))
+    });
+    let report = run_batch(
+        req.targets().ips()?,
+        |ip| {
+            reporting(
+                ip,
+                reboot_ip(&auth.target(ip), REBOOT_RECOVERY_SECONDS),
+                &results,
+            )
+        },
+        req.concurrency(),
+        runtime_handle,
+        cancel,
+    )
+    .await;
+    drop(results);
+    let _ = forwarder.await;
+
+    let done = summarize(&report?);
+    responder.send(&Event::RebootDone(done)).await
+}
+
+async fn process_restart_prover(
+    responder: &Responder,
+    req: &RestartProverRequest,
+    runtime_handle: &tokio::runtime::Handle,
+    cancel: &CancellationToken,
+) -> Result<(), AgentError> {
+    let auth = req.auth();
+    let backend = req.prover();
+    let (results, forwarder) = forward_results(responder, runtime_handle, |ip, res| {
+        Event::RestartProverResult(control_result(ip, res))
+    });
+    let report = run_batch(
+        req.targets().ips()?,
+        |ip| {
+            let restart = reboot_prover(&auth.target(ip), &backend, PROVER_RECOVERY_SECONDS);
+            reporting(ip, restart, &results)
+        },
+        req.concurrency(),
+        runtime_handle,
+        cancel,
+    )
+    .await;
+    drop(results);
+    let _ = forwarder.await;
+
+    let done = summarize(&report?);
+    responder.send(&Event::RestartProverDone(done)).await
+}
+
+// result of a reboot or prover restart, with the time the host took to come back
+fn control_result(ip: String, res: Result<u64, String>) -> ControlResult {
+    match res {
+        Ok(recovery_ms) => ControlResult {
+            ip,
+            ok: true,
+            recovery_ms: Some(recovery_ms),
+            error: None,
+        },
+        Err(e) => {
+            error!("{} failed: {}", ip, e);
+            ControlResult {
+                ip,
+                ok: false,
+                recovery_ms: None,
+                error: Some(e),
+            }
+        }
+    }
+}
+
+// upgrade the prover in place, a result per host then the summary
 async fn process_update(
-    ws_stream: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
-    ip: &str,
+    responder: &Responder,
+    req: &UpdateRequest,
     runtime_handle: &tokio::runtime::Handle,
+    cancel: &CancellationToken,
 ) -> Result<(), AgentError> {
-    Ok(())
-}
-
-// async fn process_config(
-//     ws_stream: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
-//     batch_config: &BatchConfig,
-//     runtime_handle: &tokio::runtime::Handle,
-// ) -> Result<(), AgentError> {
-//     // let result = lcd_core::config(
-//     //     runtime_handle.clone(),
-//     //     batch_config.ips.clone(),
-//     //     batch_config.pools.clone(),
-//     //     batch_config.mode.clone(),
-//     // )
-//     // .await
-//     // .unwrap();
-//     // info!("config result: {:?}", &result);
-//     // // construct json message
-
-//     // // convert result to json string
-//     // let converted = serde_json::to_string(&result).unwrap();
-
-//     // let message = serde_json::json!({
-//     //     "name": "config_result",
-//     //     "data": converted,
-//     // });
-
-//     // send_message(ws_stream, &message.to_string()).await
-//     Ok(())
-// }
+    let auth = req.auth();
+    let backend = req.prover();
+    let update = req.prover_update();
+    if req.dry_run {
+        let plans = batch_plan(
+            &req.targets(),
+            &auth,
+            &backend,
+            |installed| plan_update(installed, &update),
+            req.concurrency(),
+            runtime_handle,
+            cancel,
+        )
+        .await?;
+        return send_plans(responder, plans).await;
+    }
+
+    let (results, forwarder) = forward_results(responder, runtime_handle, update_result);
+    let report = run_batch(
+        req.targets().ips()?,
+        |ip| {
+            let updated = update_ip(&auth.target(ip), &backend, &update, PROVER_RECOVERY_SECONDS);
+            reporting(ip, updated, &results)
+        },
+        req.concurrency(),
+        runtime_handle,
+        cancel,
+    )
+    .await;
+    drop(results);
+    let _ = forwarder.await;
+
+    let done = summarize(&report?);
+    responder.send(&Event::UpdateDone(done)).await
+}
+
+fn update_result(ip: String, res: Result<RunningProver, String>) -> Event {
+    if let Err(e) = &res {
+        error!("{} update failed: {}", ip, e);
+    }
+    Event::UpdateResult(UpdateResult {
+        ip,
+        ok: res.is_ok(),
+        running: res.as_ref().ok().cloned(),
+        error: res.err(),
+    })
+}